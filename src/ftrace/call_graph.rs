@@ -0,0 +1,127 @@
+use super::manager::FuncKey;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Default)]
+pub struct CallEdge {
+    pub calls: u64,
+    // 被调用者在这条边上的总inclusive时间
+    pub inclusive: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct CallGraph {
+    edges: HashMap<(FuncKey, FuncKey), CallEdge>,
+}
+
+impl CallGraph {
+    // 压栈时调用，只增加调用次数
+    pub fn add_call(&mut self, caller: FuncKey, callee: FuncKey) {
+        self.edges.entry((caller, callee)).or_default().calls += 1;
+    }
+
+    // 弹栈时调用，累计被调用者的时间
+    pub fn add_time(&mut self, caller: FuncKey, callee: FuncKey, inclusive: u64) {
+        self.edges.entry((caller, callee)).or_default().inclusive += inclusive;
+    }
+
+    // 把其它hart的调用图合并进来
+    pub fn merge(&mut self, other: &CallGraph) {
+        for (key, other) in other.edges.iter() {
+            let edge = self.edges.entry(*key).or_default();
+            edge.calls += other.calls;
+            edge.inclusive += other.inclusive;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn edge(&self, caller: &FuncKey, callee: &FuncKey) -> Option<&CallEdge> {
+        self.edges.get(&(*caller, *callee))
+    }
+
+    // 按照调用次数从大到小排序
+    pub fn edges(&self) -> Vec<(FuncKey, FuncKey, CallEdge)> {
+        let mut vec = self
+            .edges
+            .iter()
+            .map(|((caller, callee), edge)| (*caller, *callee, *edge))
+            .collect::<Vec<_>>();
+        vec.sort_by(|a, b| {
+            b.2.calls
+                .cmp(&a.2.calls)
+                .then(b.2.inclusive.cmp(&a.2.inclusive))
+        });
+        vec
+    }
+
+    // 谁调用了callee，按照调用次数从大到小排序
+    #[cfg(test)]
+    pub fn callers_of(&self, callee: &FuncKey) -> Vec<(FuncKey, CallEdge)> {
+        self.edges()
+            .into_iter()
+            .filter(|(_, x, _)| x == callee)
+            .map(|(caller, _, edge)| (caller, edge))
+            .collect()
+    }
+
+    // caller调用了谁，按照调用次数从大到小排序
+    #[cfg(test)]
+    pub fn callees_of(&self, caller: &FuncKey) -> Vec<(FuncKey, CallEdge)> {
+        self.edges()
+            .into_iter()
+            .filter(|(x, _, _)| x == caller)
+            .map(|(_, callee, edge)| (callee, edge))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::elf_reader::FunType;
+    use super::super::manager::CurReader;
+    use super::*;
+
+    fn key(id: u32) -> FuncKey {
+        FuncKey {
+            reader: Some(CurReader::MainReader),
+            func_type: FunType::LocalFunc,
+            id,
+        }
+    }
+
+    #[test]
+    fn test_edges() {
+        let mut graph = CallGraph::default();
+        graph.add_call(key(0), key(2));
+        graph.add_call(key(1), key(2));
+        graph.add_call(key(1), key(2));
+        graph.add_time(key(1), key(2), 10);
+        graph.add_time(key(1), key(2), 5);
+        graph.add_call(key(1), key(3));
+
+        let edge = graph.edge(&key(1), &key(2)).unwrap();
+        assert!(edge.calls == 2 && edge.inclusive == 15);
+        assert!(graph.edge(&key(2), &key(1)).is_none());
+
+        let callers = graph.callers_of(&key(2));
+        assert!(callers.len() == 2);
+        assert!(callers[0].0 == key(1));
+        let callees = graph.callees_of(&key(1));
+        assert!(callees.len() == 2);
+        assert!(callees[0].0 == key(2));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut graph = CallGraph::default();
+        graph.add_call(key(1), key(2));
+        graph.add_time(key(1), key(2), 10);
+        let mut other = CallGraph::default();
+        other.add_call(key(1), key(2));
+        other.add_time(key(1), key(2), 5);
+        other.add_call(key(2), key(3));
+        graph.merge(&other);
+        let edge = graph.edge(&key(1), &key(2)).unwrap();
+        assert!(edge.calls == 2 && edge.inclusive == 15);
+        assert!(graph.edge(&key(2), &key(3)).unwrap().calls == 1);
+    }
+}
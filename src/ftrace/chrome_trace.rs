@@ -0,0 +1,193 @@
+use std::io::{self, Write};
+
+// 一次调用，输出为Chrome Trace的X事件，时间的单位是纳秒
+pub struct Slice<'a> {
+    pub name: &'a str,
+    // 所属的reader
+    pub cat: &'a str,
+    pub hart: u32,
+    pub tid: u32,
+    pub start: u64,
+    pub dur: u64,
+    pub args: &'a [(&'a str, String)],
+}
+
+// 按照Chrome Trace Event Format写出JSON，hart对应pid，上下文对应tid，
+// 这样每个hart的每个上下文都有自己的一条时间线。
+// JSON的数字按照双精度浮点数解析，所以tid由调用者分配成较小的编号，上下文的值放在线程名里
+pub struct ChromeWriter<W: Write> {
+    out: W,
+    first: bool,
+}
+
+impl<W: Write> ChromeWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        write!(out, "{{\"traceEvents\":[")?;
+        Ok(ChromeWriter { out, first: true })
+    }
+
+    fn begin_event(&mut self) -> io::Result<()> {
+        if self.first {
+            self.first = false;
+            writeln!(self.out)
+        } else {
+            writeln!(self.out, ",")
+        }
+    }
+
+    pub fn process_name(&mut self, hart: u32, name: &str) -> io::Result<()> {
+        self.begin_event()?;
+        write!(
+            self.out,
+            "{{\"ph\":\"M\",\"name\":\"process_name\",\"pid\":{},\"args\":{{\"name\":{}}}}}",
+            hart,
+            escape(name)
+        )
+    }
+
+    pub fn thread_name(&mut self, hart: u32, tid: u32, name: &str) -> io::Result<()> {
+        self.begin_event()?;
+        write!(
+            self.out,
+            "{{\"ph\":\"M\",\"name\":\"thread_name\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":{}}}}}",
+            hart,
+            tid,
+            escape(name)
+        )
+    }
+
+    pub fn complete(&mut self, slice: &Slice) -> io::Result<()> {
+        self.begin_event()?;
+        write!(
+            self.out,
+            "{{\"ph\":\"X\",\"name\":{},\"cat\":{},\"pid\":{},\"tid\":{},\"ts\":{},\"dur\":{}",
+            escape(slice.name),
+            escape(slice.cat),
+            slice.hart,
+            slice.tid,
+            micros(slice.start),
+            micros(slice.dur)
+        )?;
+        if !slice.args.is_empty() {
+            let args = slice
+                .args
+                .iter()
+                .map(|(key, value)| format!("{}:{}", escape(key), escape(value)))
+                .collect::<Vec<_>>();
+            write!(self.out, ",\"args\":{{{}}}", args.join(","))?;
+        }
+        write!(self.out, "}}")
+    }
+
+    // 某个时刻的标记，只画在所属的线程上
+    pub fn instant(&mut self, hart: u32, tid: u32, name: &str, time: u64) -> io::Result<()> {
+        self.begin_event()?;
+        write!(
+            self.out,
+            "{{\"ph\":\"i\",\"s\":\"t\",\"name\":{},\"cat\":\"marker\",\"pid\":{},\"tid\":{},\"ts\":{}}}",
+            escape(name),
+            hart,
+            tid,
+            micros(time)
+        )
+    }
+
+    // 区间可能和函数交错，所以用异步事件画在单独的轨道上，id在同一个hart内唯一
+    pub fn region(
+        &mut self,
+        hart: u32,
+        id: usize,
+        name: &str,
+        begin: u64,
+        end: u64,
+    ) -> io::Result<()> {
+        for (ph, time) in [("b", begin), ("e", end)] {
+            self.begin_event()?;
+            write!(
+                self.out,
+                "{{\"ph\":\"{}\",\"name\":{},\"cat\":\"region\",\"id\":{},\"pid\":{},\"ts\":{}}}",
+                ph,
+                escape(name),
+                id,
+                hart,
+                micros(time)
+            )?;
+        }
+        Ok(())
+    }
+
+    // unit是原始时钟的单位，记录在otherData中
+    pub fn finish(mut self, unit: &str) -> io::Result<W> {
+        writeln!(
+            self.out,
+            "\n],\"displayTimeUnit\":\"ns\",\"otherData\":{{\"clock\":{}}}}}",
+            escape(unit)
+        )?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// Chrome Trace的时间单位是微秒
+fn micros(nanos: u64) -> String {
+    format!("{}.{:03}", nanos / 1000, nanos % 1000)
+}
+
+// 转换成带引号的JSON字符串
+fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len() + 2);
+    res.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert!(escape("a\"b\\c\n\u{1}") == "\"a\\\"b\\\\c\\n\\u0001\"");
+        assert!(micros(1_234_567) == "1234.567");
+    }
+
+    #[test]
+    fn test_chrome_writer() {
+        let mut writer = ChromeWriter::new(Vec::new()).unwrap();
+        writer.process_name(0, "hart 0").unwrap();
+        let args = [("args", "(a0=\"hi\")".to_string())];
+        writer
+            .complete(&Slice {
+                name: "main",
+                cat: "dummy",
+                hart: 0,
+                tid: 1,
+                start: 2000,
+                dur: 500,
+                args: &args,
+            })
+            .unwrap();
+        writer.instant(0, 1, "check", 2100).unwrap();
+        writer.region(0, 0, "case1", 2000, 2500).unwrap();
+        let text = String::from_utf8(writer.finish("inst").unwrap()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines[0] == "{\"traceEvents\":[");
+        assert!(lines.len() == 7);
+        assert!(lines[2].starts_with("{\"ph\":\"X\",\"name\":\"main\",\"cat\":\"dummy\""));
+        assert!(lines[2].contains("\"ts\":2.000,\"dur\":0.500"));
+        assert!(lines[2].ends_with("\"args\":{\"args\":\"(a0=\\\"hi\\\")\"}},"));
+        assert!(lines[4].starts_with("{\"ph\":\"b\""));
+        assert!(lines[6] == "],\"displayTimeUnit\":\"ns\",\"otherData\":{\"clock\":\"inst\"}}");
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum ClockType {
+    // 主机的墙上时间，毫秒
+    WallMillis,
+    // 客户机执行的指令数，由check_instruction推进
+    Instret,
+    // 由调用者提供的周期数，比如NPC/Verilator的仿真周期
+    Cycle,
+    // 由调用者提供的mtime
+    Mtime,
+    // 主机的单调时钟，纳秒
+    MonotonicNanos,
+}
+
+impl ClockType {
+    // 与lib.rs中导出给C的CLOCK_*常量一一对应
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(ClockType::WallMillis),
+            1 => Some(ClockType::Instret),
+            2 => Some(ClockType::Cycle),
+            3 => Some(ClockType::Mtime),
+            4 => Some(ClockType::MonotonicNanos),
+            _ => None,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            ClockType::WallMillis => "ms",
+            ClockType::Instret => "inst",
+            ClockType::Cycle => "cycle",
+            ClockType::Mtime => "mtime",
+            ClockType::MonotonicNanos => "ns",
+        }
+    }
+
+    // 时间线工具需要真实的时间单位，指令数、周期数等按照每个单位1纳秒处理
+    pub fn to_nanos(self, value: u64) -> u64 {
+        match self {
+            ClockType::WallMillis => value.saturating_mul(1_000_000),
+            _ => value,
+        }
+    }
+}
+
+pub struct Clock {
+    clock_type: ClockType,
+    // Instret/Cycle/Mtime的当前计数
+    counter: u64,
+    init_instant: Instant,
+    init_millis: u64,
+}
+
+impl Clock {
+    pub fn new(clock_type: ClockType) -> Self {
+        Clock {
+            clock_type,
+            counter: 0,
+            init_instant: Instant::now(),
+            init_millis: Self::wall_millis(),
+        }
+    }
+
+    // 给另一个hart使用的时钟，墙上时间和单调时钟共用同一个起点，不同hart的时间可以直接比较。
+    // Instret按各自hart执行的指令数计数，Cycle和Mtime由调用者分别设置，
+    // 这三种时钟只有在调用者保证各个hart同步推进时才能比较
+    pub fn fork(&self) -> Self {
+        Clock {
+            clock_type: self.clock_type,
+            counter: 0,
+            init_instant: self.init_instant,
+            init_millis: self.init_millis,
+        }
+    }
+
+    fn wall_millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    pub fn clock_type(&self) -> ClockType {
+        self.clock_type
+    }
+
+    // 返回从初始化开始经过的时间，单位由clock_type决定
+    pub fn now(&self) -> u64 {
+        match self.clock_type {
+            ClockType::WallMillis => Self::wall_millis() - self.init_millis,
+            ClockType::Instret | ClockType::Cycle | ClockType::Mtime => self.counter,
+            ClockType::MonotonicNanos => self.init_instant.elapsed().as_nanos() as u64,
+        }
+    }
+
+    // 每执行一条指令调用一次，只对Instret生效
+    pub fn tick(&mut self) {
+        if self.clock_type == ClockType::Instret {
+            self.counter += 1;
+        }
+    }
+
+    // 由调用者提供时间，只对Cycle和Mtime生效
+    pub fn set(&mut self, value: u64) -> Result<(), isize> {
+        match self.clock_type {
+            ClockType::Cycle | ClockType::Mtime => {
+                self.counter = value;
+                Ok(())
+            }
+            _ => {
+                println!("Warning: clock {:?} can not be set", self.clock_type);
+                Err(-1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instret_clock() {
+        let mut clock = Clock::new(ClockType::Instret);
+        assert!(clock.now() == 0);
+        for _ in 0..10 {
+            clock.tick();
+        }
+        assert!(clock.now() == 10);
+        assert!(clock.set(100).is_err());
+        assert!(clock.now() == 10);
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let mut clock = Clock::new(ClockType::Cycle);
+        clock.tick();
+        assert!(clock.now() == 0);
+        assert!(clock.set(1234).is_ok());
+        assert!(clock.now() == 1234);
+
+        let mut clock = Clock::new(ClockType::from_code(3).unwrap());
+        assert!(clock.clock_type() == ClockType::Mtime);
+        assert!(clock.set(42).is_ok());
+        assert!(clock.now() == 42);
+        assert!(ClockType::from_code(5).is_none());
+        assert!(ClockType::WallMillis.to_nanos(3) == 3_000_000);
+        assert!(ClockType::Cycle.to_nanos(3) == 3);
+    }
+
+    #[test]
+    fn test_monotonic_clock() {
+        let clock = Clock::new(ClockType::MonotonicNanos);
+        let t0 = clock.now();
+        std::thread::sleep(std::time::Duration::from_micros(10));
+        assert!(clock.now() > t0);
+        // fork出来的时钟和原来的时钟共用起点
+        let fork = clock.fork();
+        assert!(fork.now() > t0);
+        let mut instret = Clock::new(ClockType::Instret);
+        instret.tick();
+        assert!(instret.fork().now() == 0);
+    }
+}
@@ -0,0 +1,668 @@
+use gimli::{AttributeValue, Dwarf, EndianSlice, RunTimeEndian, SectionId, UnitOffset};
+use std::collections::HashMap;
+
+// riscv的调用约定用a0到a7传递参数
+pub const MAX_REG_ARGS: usize = 8;
+// 通用寄存器的字节数
+const XLEN_BYTES: u64 = 8;
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+// ELF头e_flags中的EF_RISCV_FLOAT_ABI，决定浮点参数是否通过浮点寄存器传递
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FloatAbi {
+    Soft,
+    Single,
+    Double,
+    Quad,
+}
+
+impl FloatAbi {
+    pub fn from_e_flags(e_flags: u32) -> Self {
+        match e_flags & 0x6 {
+            0x0 => FloatAbi::Soft,
+            0x2 => FloatAbi::Single,
+            0x4 => FloatAbi::Double,
+            _ => FloatAbi::Quad,
+        }
+    }
+
+    // 浮点寄存器能放下的最大字节数，更宽的浮点数按照整数传递
+    fn flen(self) -> u64 {
+        match self {
+            FloatAbi::Soft => 0,
+            FloatAbi::Single => 4,
+            FloatAbi::Double => 8,
+            FloatAbi::Quad => 16,
+        }
+    }
+}
+
+// 参数和返回值的类型，只区分格式化时需要的信息
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ValueType {
+    // 没有类型信息，按照寄存器原样输出
+    #[default]
+    Unknown,
+    // 有符号和无符号整数，参数是字节数
+    Signed(u64),
+    Unsigned(u64),
+    Bool,
+    // 浮点数在fa寄存器里，没有记录
+    Float(u64),
+    // soft-float或者比浮点寄存器宽的浮点数，和同样大小的整数一样使用整数寄存器
+    IntFloat(u64),
+    Pointer,
+    // 指向char的指针，调用时从客户机内存中读取字符串
+    Str,
+    Enum {
+        size: u64,
+        // 枚举值和名字
+        variants: Vec<(u64, String)>,
+    },
+    // 结构体和联合体，参数是字节数
+    Aggregate(u64),
+}
+
+impl ValueType {
+    fn size(&self) -> u64 {
+        match self {
+            ValueType::Unknown | ValueType::Pointer | ValueType::Str => XLEN_BYTES,
+            ValueType::Bool => 1,
+            ValueType::Signed(size)
+            | ValueType::Unsigned(size)
+            | ValueType::Float(size)
+            | ValueType::IntFloat(size)
+            | ValueType::Enum { size, .. }
+            | ValueType::Aggregate(size) => *size,
+        }
+    }
+
+    // 超过两个寄存器的值由调用者分配空间，通过指针传递
+    fn by_ref(&self) -> bool {
+        self.size() > 2 * XLEN_BYTES
+    }
+
+    // 占用的整数参数寄存器个数
+    fn reg_slots(&self) -> usize {
+        match self {
+            ValueType::Float(_) => 0,
+            _ if self.by_ref() => 1,
+            _ if self.size() > XLEN_BYTES => 2,
+            _ => 1,
+        }
+    }
+
+    // hi是a1中的高位部分，只有跨两个寄存器的值才会用到
+    pub fn format(&self, lo: u64, hi: Option<u64>) -> String {
+        let size = self.size();
+        let wide = (hi.unwrap_or(0) as u128) << 64 | lo as u128;
+        match self {
+            ValueType::Unknown => format!("{:#x}", lo),
+            ValueType::Signed(_) if size > XLEN_BYTES => format!("{}", wide as i128),
+            ValueType::Unsigned(_) if size > XLEN_BYTES => format!("{}", wide),
+            ValueType::Signed(_) => {
+                let shift = 64 - size.clamp(1, XLEN_BYTES) * 8;
+                format!("{}", ((lo << shift) as i64) >> shift)
+            }
+            ValueType::Unsigned(_) => format!("{}", truncate(lo, size)),
+            ValueType::Bool => format!("{}", truncate(lo, size) != 0),
+            ValueType::Float(_) => "?".to_string(),
+            ValueType::IntFloat(4) => format!("{}", f32::from_bits(lo as u32)),
+            ValueType::IntFloat(8) => format!("{}", f64::from_bits(lo)),
+            // 没有128位浮点数的类型，只输出原始的值
+            ValueType::IntFloat(_) => format!("{:#x}", wide),
+            // 没有读到字符串时只输出地址
+            ValueType::Pointer | ValueType::Str => format!("{:#x}", lo),
+            ValueType::Enum { variants, .. } => {
+                let value = truncate(lo, size);
+                match variants.iter().find(|(x, _)| truncate(*x, size) == value) {
+                    Some((_, name)) => name.clone(),
+                    None => format!("{}", value),
+                }
+            }
+            ValueType::Aggregate(_) if self.by_ref() => format!("&{:#x}", lo),
+            ValueType::Aggregate(_) if size > XLEN_BYTES => {
+                format!("{{{:#x}, {:#x}}}", lo, hi.unwrap_or(0))
+            }
+            ValueType::Aggregate(_) => format!("{{{:#x}}}", truncate(lo, size)),
+        }
+    }
+}
+
+fn truncate(value: u64, size: u64) -> u64 {
+    if size >= XLEN_BYTES {
+        value
+    } else {
+        value & ((1 << (size * 8)) - 1)
+    }
+}
+
+// 函数的签名，来自DWARF或者用户提供的签名表
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncSig {
+    // 具名参数的类型
+    pub params: Vec<ValueType>,
+    // 是否有...这样的可变参数
+    pub variadic: bool,
+    // 返回值的类型，None表示void
+    pub ret: Option<ValueType>,
+}
+
+impl Default for FuncSig {
+    fn default() -> Self {
+        FuncSig::untyped(0, false)
+    }
+}
+
+impl FuncSig {
+    // 只知道参数个数的签名，返回值按照原样输出
+    pub fn untyped(arity: usize, variadic: bool) -> Self {
+        FuncSig {
+            params: vec![ValueType::Unknown; arity],
+            variadic,
+            ret: Some(ValueType::Unknown),
+        }
+    }
+
+    // 把第index个参数当作char*，参数不够时用未知类型补齐
+    pub fn set_string_arg(&mut self, index: usize) {
+        if self.params.len() <= index {
+            self.params.resize(index + 1, ValueType::Unknown);
+        }
+        self.params[index] = ValueType::Str;
+    }
+
+    // 把other中的字符串参数合并进来，用于用户在DWARF或者已有签名的基础上指定字符串参数
+    pub fn merge_strings(&mut self, other: &FuncSig) {
+        for (idx, param) in other.params.iter().enumerate() {
+            if *param == ValueType::Str {
+                self.set_string_arg(idx);
+            }
+        }
+    }
+
+    pub fn has_strings(&self) -> bool {
+        self.params.contains(&ValueType::Str)
+    }
+
+    // 返回值太大时，a0是调用者分配的返回值空间的地址
+    fn sret(&self) -> bool {
+        self.ret.as_ref().is_some_and(|x| x.by_ref())
+    }
+
+    // 需要记录的参数寄存器个数，可变参数的函数无法确定，只能全部记录
+    pub fn reg_count(&self) -> usize {
+        if self.variadic {
+            return MAX_REG_ARGS;
+        }
+        let slots = self.params.iter().map(|x| x.reg_slots()).sum::<usize>();
+        (slots + self.sret() as usize).min(MAX_REG_ARGS)
+    }
+
+    // 每个参数所在的第一个整数寄存器，浮点参数为None
+    fn layout(&self) -> Vec<(&ValueType, Option<usize>)> {
+        let mut reg = self.sret() as usize;
+        self.params
+            .iter()
+            .map(|param| {
+                let slots = param.reg_slots();
+                let first = (slots > 0).then_some(reg);
+                reg += slots;
+                (param, first)
+            })
+            .collect()
+    }
+
+    // 字符串参数所在的寄存器
+    pub fn string_regs(&self) -> Vec<usize> {
+        self.layout()
+            .into_iter()
+            .filter(|(param, _)| **param == ValueType::Str)
+            .filter_map(|(_, reg)| reg)
+            .filter(|reg| *reg < MAX_REG_ARGS)
+            .collect()
+    }
+
+    // 按照寄存器的分配输出参数，放不进寄存器的参数在栈上，不输出，
+    // 从a7开始的两倍XLEN参数高半部分在栈上，只输出低半部分并标记为不完整
+    // strings是调用时读取到的字符串参数，按照寄存器编号索引
+    pub fn format_args(&self, args: &[u64], strings: &[(usize, String)]) -> String {
+        let mut list = Vec::new();
+        // 浮点参数按顺序使用fa寄存器
+        let mut fregs = 0..;
+        for (param, reg) in self.layout() {
+            let Some(reg) = reg else {
+                let freg = fregs.next().unwrap_or_default();
+                list.push(format!("fa{}={}", freg, param.format(0, None)));
+                continue;
+            };
+            let Some(&lo) = args.get(reg) else {
+                break;
+            };
+            if let Some((_, text)) = strings.iter().find(|(x, _)| *x == reg) {
+                list.push(format!("a{}={}", reg, text));
+            } else if param.reg_slots() == 2 {
+                match args.get(reg + 1).filter(|_| reg + 1 < MAX_REG_ARGS) {
+                    Some(&hi) => list.push(format!(
+                        "a{}:a{}={}",
+                        reg,
+                        reg + 1,
+                        param.format(lo, Some(hi))
+                    )),
+                    None => list.push(format!("a{}={:#x}…", reg, lo)),
+                }
+            } else {
+                list.push(format!("a{}={}", reg, param.format(lo, None)));
+            }
+        }
+        if self.variadic {
+            list.push("...".to_string());
+        }
+        format!("({})", list.join(", "))
+    }
+
+    // void函数没有返回值
+    pub fn format_ret(&self, ret_val: (u64, Option<u64>)) -> Option<String> {
+        let ret = self.ret.as_ref()?;
+        Some(ret.format(ret_val.0, ret_val.1))
+    }
+}
+
+// 把读取到的调试信息段交给gimli，缺少的段当作空段
+fn load_dwarf<'a>(
+    sections: &'a HashMap<SectionId, Vec<u8>>,
+    endian: RunTimeEndian,
+) -> Result<Dwarf<Reader<'a>>, gimli::Error> {
+    Dwarf::load(|id| -> Result<_, gimli::Error> {
+        let data = sections.get(&id).map(|x| x.as_slice()).unwrap_or(&[]);
+        Ok(EndianSlice::new(data, endian))
+    })
+}
+
+// 解析一个编译单元中的类型，同一个类型会被很多函数引用，所以缓存起来
+struct TypeParser<'a, 'd> {
+    dwarf: &'d Dwarf<Reader<'a>>,
+    unit: &'d gimli::Unit<Reader<'a>>,
+    cache: HashMap<UnitOffset, ValueType>,
+    float_abi: FloatAbi,
+}
+
+impl TypeParser<'_, '_> {
+    // 条目的DW_AT_type，没有这个属性时返回None
+    fn type_ref(&self, offset: UnitOffset) -> Option<Option<UnitOffset>> {
+        let entry = self.unit.entry(offset).ok()?;
+        match entry.attr_value(gimli::DW_AT_type).ok()? {
+            Some(AttributeValue::UnitRef(x)) => Some(Some(x)),
+            // 跨编译单元的引用不处理
+            Some(_) => Some(None),
+            None => None,
+        }
+    }
+
+    // 参数和返回值的类型，自身没有类型时沿着abstract_origin和specification查找
+    fn entry_type(&mut self, offset: UnitOffset) -> Option<ValueType> {
+        let mut cur = offset;
+        // 防止循环引用
+        for _ in 0..8 {
+            match self.type_ref(cur) {
+                Some(Some(ty)) => return Some(self.parse(ty, 0)),
+                Some(None) => return Some(ValueType::Unknown),
+                None => {}
+            }
+            let entry = self.unit.entry(cur).ok()?;
+            cur = [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification]
+                .into_iter()
+                .find_map(|attr| match entry.attr_value(attr) {
+                    Ok(Some(AttributeValue::UnitRef(x))) => Some(x),
+                    _ => None,
+                })?;
+        }
+        None
+    }
+
+    fn parse(&mut self, offset: UnitOffset, depth: usize) -> ValueType {
+        if let Some(ty) = self.cache.get(&offset) {
+            return ty.clone();
+        }
+        let ty = self.parse_uncached(offset, depth).unwrap_or_default();
+        self.cache.insert(offset, ty.clone());
+        ty
+    }
+
+    fn parse_uncached(&mut self, offset: UnitOffset, depth: usize) -> Option<ValueType> {
+        let entry = self.unit.entry(offset).ok()?;
+        let size = entry
+            .attr_value(gimli::DW_AT_byte_size)
+            .ok()
+            .flatten()
+            .and_then(|x| x.udata_value());
+        let ty = match entry.tag() {
+            gimli::DW_TAG_base_type => {
+                let size = size?;
+                match entry.attr_value(gimli::DW_AT_encoding).ok()?? {
+                    AttributeValue::Encoding(gimli::DW_ATE_boolean) => ValueType::Bool,
+                    AttributeValue::Encoding(gimli::DW_ATE_signed)
+                    | AttributeValue::Encoding(gimli::DW_ATE_signed_char) => {
+                        ValueType::Signed(size)
+                    }
+                    AttributeValue::Encoding(gimli::DW_ATE_float)
+                        if size <= self.float_abi.flen() =>
+                    {
+                        ValueType::Float(size)
+                    }
+                    AttributeValue::Encoding(gimli::DW_ATE_float) => ValueType::IntFloat(size),
+                    AttributeValue::Encoding(_) => ValueType::Unsigned(size),
+                    _ => return None,
+                }
+            }
+            gimli::DW_TAG_pointer_type => match self.type_ref(offset) {
+                Some(Some(ty)) if self.is_char(ty, depth) => ValueType::Str,
+                _ => ValueType::Pointer,
+            },
+            gimli::DW_TAG_reference_type | gimli::DW_TAG_rvalue_reference_type => {
+                ValueType::Pointer
+            }
+            gimli::DW_TAG_enumeration_type => ValueType::Enum {
+                size: size?,
+                variants: self.enumerators(offset),
+            },
+            gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type | gimli::DW_TAG_class_type => {
+                ValueType::Aggregate(size?)
+            }
+            // typedef和const等修饰符使用被修饰的类型
+            gimli::DW_TAG_typedef
+            | gimli::DW_TAG_const_type
+            | gimli::DW_TAG_volatile_type
+            | gimli::DW_TAG_restrict_type
+            | gimli::DW_TAG_atomic_type => {
+                if depth > 16 {
+                    return None;
+                }
+                let ty = self.type_ref(offset)??;
+                self.parse(ty, depth + 1)
+            }
+            _ => return None,
+        };
+        Some(ty)
+    }
+
+    // 去掉typedef和const等修饰之后是否是单字节的char
+    fn is_char(&self, offset: UnitOffset, depth: usize) -> bool {
+        let Ok(entry) = self.unit.entry(offset) else {
+            return false;
+        };
+        match entry.tag() {
+            gimli::DW_TAG_base_type => {
+                let size = entry.attr_value(gimli::DW_AT_byte_size).ok().flatten();
+                let encoding = entry.attr_value(gimli::DW_AT_encoding).ok().flatten();
+                size.and_then(|x| x.udata_value()) == Some(1)
+                    && matches!(
+                        encoding,
+                        Some(AttributeValue::Encoding(gimli::DW_ATE_signed_char))
+                            | Some(AttributeValue::Encoding(gimli::DW_ATE_unsigned_char))
+                    )
+            }
+            gimli::DW_TAG_typedef
+            | gimli::DW_TAG_const_type
+            | gimli::DW_TAG_volatile_type
+            | gimli::DW_TAG_restrict_type
+            | gimli::DW_TAG_atomic_type
+                if depth <= 16 =>
+            {
+                match self.type_ref(offset) {
+                    Some(Some(ty)) => self.is_char(ty, depth + 1),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn enumerators(&self, offset: UnitOffset) -> Vec<(u64, String)> {
+        let mut variants = Vec::new();
+        let Ok(mut tree) = self.unit.entries_tree(Some(offset)) else {
+            return variants;
+        };
+        let Ok(root) = tree.root() else {
+            return variants;
+        };
+        let mut children = root.children();
+        while let Ok(Some(child)) = children.next() {
+            let entry = child.entry();
+            if entry.tag() != gimli::DW_TAG_enumerator {
+                continue;
+            }
+            let value = match entry.attr_value(gimli::DW_AT_const_value) {
+                Ok(Some(x)) => x.udata_value().or(x.sdata_value().map(|x| x as u64)),
+                _ => None,
+            };
+            let name = match entry.attr_value(gimli::DW_AT_name) {
+                Ok(Some(x)) => self.dwarf.attr_string(self.unit, x).ok(),
+                _ => None,
+            };
+            if let (Some(value), Some(name)) = (value, name) {
+                variants.push((value, name.to_string_lossy().into_owned()));
+            }
+        }
+        variants
+    }
+}
+
+// 按照函数的起始地址索引所有有地址的函数的签名，没有调试信息的时候为空
+pub fn parse_signatures(
+    mut load: impl FnMut(&str) -> Option<Vec<u8>>,
+    little_endian: bool,
+    float_abi: FloatAbi,
+) -> HashMap<u64, FuncSig> {
+    let mut sigs = HashMap::new();
+    let endian = if little_endian {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let sections = [
+        SectionId::DebugInfo,
+        SectionId::DebugAbbrev,
+        SectionId::DebugStr,
+        // 解析编译单元时会读取行号表
+        SectionId::DebugLine,
+        SectionId::DebugLineStr,
+        SectionId::DebugAddr,
+        SectionId::DebugStrOffsets,
+        SectionId::DebugRanges,
+        SectionId::DebugRngLists,
+    ]
+    .into_iter()
+    .filter_map(|id| load(id.name()).map(|data| (id, data)))
+    .collect::<HashMap<_, _>>();
+    if !sections.contains_key(&SectionId::DebugInfo) {
+        return sigs;
+    }
+    let Ok(dwarf) = load_dwarf(&sections, endian) else {
+        return sigs;
+    };
+
+    let mut units = dwarf.units();
+    while let Ok(Some(header)) = units.next() {
+        let Ok(unit) = dwarf.unit(header) else {
+            continue;
+        };
+        let mut types = TypeParser {
+            dwarf: &dwarf,
+            unit: &unit,
+            cache: HashMap::new(),
+            float_abi,
+        };
+        let mut entries = unit.entries();
+        let mut depth = 0;
+        // 正在遍历的函数的深度和起始地址
+        let mut cur: Option<(isize, u64)> = None;
+        while let Ok(Some((delta, entry))) = entries.next_dfs() {
+            depth += delta;
+            if cur.is_some_and(|(cur_depth, _)| depth <= cur_depth) {
+                cur = None;
+            }
+            match entry.tag() {
+                gimli::DW_TAG_subprogram => {
+                    let low_pc = match entry.attr_value(gimli::DW_AT_low_pc) {
+                        Ok(Some(value)) => dwarf.attr_address(&unit, value).ok().flatten(),
+                        _ => None,
+                    };
+                    // 只有声明没有地址的函数不需要记录
+                    if let Some(low_pc) = low_pc {
+                        let sig = FuncSig {
+                            params: Vec::new(),
+                            variadic: false,
+                            ret: types.entry_type(entry.offset()),
+                        };
+                        sigs.insert(low_pc, sig);
+                        cur = Some((depth, low_pc));
+                    }
+                }
+                gimli::DW_TAG_formal_parameter | gimli::DW_TAG_unspecified_parameters => {
+                    // 只统计函数的直接子节点，嵌套的函数声明里的参数不算
+                    if let Some((cur_depth, low_pc)) = cur {
+                        if depth == cur_depth + 1 {
+                            let ty = if entry.tag() == gimli::DW_TAG_formal_parameter {
+                                Some(types.entry_type(entry.offset()).unwrap_or_default())
+                            } else {
+                                None
+                            };
+                            let sig = sigs.get_mut(&low_pc).expect("Signature should exist");
+                            match ty {
+                                Some(ty) => sig.params.push(ty),
+                                None => sig.variadic = true,
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    sigs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reg_count() {
+        let sig = FuncSig::untyped(2, false);
+        assert!(sig.reg_count() == 2);
+        let sig = FuncSig::untyped(10, false);
+        assert!(sig.reg_count() == MAX_REG_ARGS);
+        let sig = FuncSig::untyped(1, true);
+        assert!(sig.reg_count() == MAX_REG_ARGS);
+        // 128位整数占两个寄存器，大结构体通过指针传递，浮点数不占用整数寄存器
+        let sig = FuncSig {
+            params: vec![
+                ValueType::Unsigned(16),
+                ValueType::Aggregate(32),
+                ValueType::Float(8),
+            ],
+            variadic: false,
+            ret: Some(ValueType::Aggregate(24)),
+        };
+        assert!(sig.reg_count() == 4);
+        // soft-float时浮点数和整数一样使用整数寄存器
+        let sig = FuncSig {
+            params: vec![
+                ValueType::IntFloat(8),
+                ValueType::Str,
+                ValueType::IntFloat(16),
+            ],
+            variadic: false,
+            ret: None,
+        };
+        assert!(sig.reg_count() == 4);
+        assert!(sig.string_regs() == [1]);
+        assert!(
+            sig.format_args(&[2.5_f64.to_bits(), 0x1000, 1, 0], &[])
+                == "(a0=2.5, a1=0x1000, a2:a3=0x1)"
+        );
+        assert!(FloatAbi::from_e_flags(0x5) == FloatAbi::Double);
+        assert!(FloatAbi::from_e_flags(0x1) == FloatAbi::Soft);
+    }
+
+    #[test]
+    fn test_format() {
+        assert!(ValueType::Signed(4).format(0xffff_ffff, None) == "-1");
+        assert!(ValueType::Signed(8).format(u64::MAX, None) == "-1");
+        assert!(ValueType::Unsigned(1).format(0x1ff, None) == "255");
+        assert!(ValueType::Bool.format(1, None) == "true");
+        assert!(ValueType::Pointer.format(0x8000_0000, None) == "0x80000000");
+        let color = ValueType::Enum {
+            size: 4,
+            variants: vec![(0, "RED".to_string()), (1, "GREEN".to_string())],
+        };
+        assert!(color.format(1, None) == "GREEN");
+        assert!(color.format(7, None) == "7");
+        // 跨a0和a1的值需要拼起来
+        assert!(ValueType::Signed(16).format(u64::MAX, Some(u64::MAX)) == "-1");
+        assert!(ValueType::Unsigned(16).format(0, Some(1)) == "18446744073709551616");
+        assert!(ValueType::Aggregate(16).format(1, Some(2)) == "{0x1, 0x2}");
+
+        let sig = FuncSig {
+            params: vec![
+                ValueType::Signed(4),
+                ValueType::Unsigned(16),
+                ValueType::Float(8),
+                ValueType::Bool,
+            ],
+            variadic: true,
+            ret: None,
+        };
+        let text = sig.format_args(&[u64::MAX, 2, 0, 1], &[]);
+        assert!(text == "(a0=-1, a1:a2=2, fa0=?, a3=true, ...)");
+        assert!(sig.format_ret((0, Some(0))).is_none());
+        // 返回大结构体时a0是返回值的地址
+        let sig = FuncSig {
+            params: vec![ValueType::Signed(8)],
+            variadic: false,
+            ret: Some(ValueType::Aggregate(32)),
+        };
+        assert!(sig.format_args(&[0x100, 3], &[]) == "(a1=3)");
+        assert!(sig.format_ret((0x100, Some(0))).as_deref() == Some("&0x100"));
+
+        // 读到的字符串代替地址输出
+        let sig = FuncSig {
+            params: vec![ValueType::Str, ValueType::Str],
+            variadic: true,
+            ret: Some(ValueType::Signed(4)),
+        };
+        assert!(sig.string_regs() == [0, 1]);
+        let strings = [(0, "\"%d\\n\"".to_string())];
+        assert!(sig.format_args(&[0x1000, 0x2000], &strings) == "(a0=\"%d\\n\", a1=0x2000, ...)");
+
+        // 从a7开始的128位参数高半部分在栈上
+        let mut params = vec![ValueType::Signed(8); 7];
+        params.push(ValueType::Unsigned(16));
+        let sig = FuncSig {
+            params,
+            variadic: false,
+            ret: None,
+        };
+        let text = sig.format_args(&[0, 0, 0, 0, 0, 0, 0, 0x1234], &[]);
+        assert!(text.ends_with(", a6=0, a7=0x1234…)"));
+    }
+
+    #[test]
+    fn test_merge_strings() {
+        let mut user = FuncSig::untyped(0, false);
+        user.set_string_arg(1);
+        assert!(user.params == [ValueType::Unknown, ValueType::Str]);
+        // 之后指定的参数个数不会丢掉字符串参数
+        let mut sig = FuncSig::untyped(3, true);
+        sig.merge_strings(&user);
+        assert!(sig.params[1] == ValueType::Str && sig.params.len() == 3);
+        assert!(sig.variadic && sig.has_strings());
+    }
+
+    #[test]
+    fn test_no_debug_info() {
+        assert!(parse_signatures(|_| None, true, FloatAbi::Double).is_empty());
+    }
+}
@@ -1,6 +1,12 @@
-use elf::{abi::STT_FUNC, endian::AnyEndian, ElfStream};
+use elf::{
+    abi::{EM_RISCV, STT_FUNC},
+    endian::AnyEndian,
+    ElfStream,
+};
 use std::{cmp::Ordering, fs::File, path::PathBuf};
 
+use super::dwarf::{parse_signatures, FloatAbi, FuncSig};
+use super::unwind::CfiTable;
 use crate::{debug_print, debug_println};
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
@@ -17,6 +23,8 @@ pub struct Func {
     pub name: String,
     pub start: u64,
     pub end: u64,
+    // 从DWARF中得到的签名，没有调试信息时为None
+    pub sig: Option<FuncSig>,
 }
 
 #[derive(Clone)]
@@ -25,19 +33,31 @@ pub struct ElfReader {
     pub name: String,
     pub start: u64,
     pub end: u64,
+    // 所属的地址空间，None表示在所有地址空间都可见（比如内核）
+    pub asid: Option<u64>,
     func_vec: Vec<Func>,
+    // 回溯用的.eh_frame和.debug_frame
+    cfi: CfiTable,
 }
 
 impl ElfReader {
-    pub fn new(id: u32, file: &str) -> Self {
+    // 运行时也会加载新的程序，所以文件有问题的时候返回错误而不是panic
+    pub fn new(id: u32, file: &str) -> Result<Self, isize> {
         debug_println!("Elf file: {}", file);
+        let path = file;
         let file = &PathBuf::from(file);
-        let name = file
-            .file_stem()
-            .and_then(|f| f.to_str())
-            .expect("Can not Convert to Str");
-        let io = File::open(file).expect("Can not open file");
-        let mut file_stream = ElfStream::<AnyEndian, _>::open_stream(io).expect("Open Failed");
+        let Some(name) = file.file_stem().and_then(|f| f.to_str()) else {
+            println!("Warning: invalid elf path {}", path);
+            return Err(-1);
+        };
+        let io = File::open(file).map_err(|err| {
+            println!("Warning: can not open elf {}: {}", path, err);
+            -1_isize
+        })?;
+        let mut file_stream = ElfStream::<AnyEndian, _>::open_stream(io).map_err(|err| {
+            println!("Warning: can not parse elf {}: {}", path, err);
+            -1_isize
+        })?;
 
         // let text_shdr = *file_stream.section_header_by_name(".text")
         // .expect("Section table should be parseable")
@@ -46,18 +66,18 @@ impl ElfReader {
         // let start = text_shdr.sh_addr;
         // let end = start + text_shdr.sh_offset;
 
-        let (sym_t, str_t) = file_stream
-            .symbol_table()
-            .expect("Section table should be parseable")
-            .expect("File should have a .Symtab section");
+        // strip过的程序没有.symtab，无法找到函数
+        let Ok(Some((sym_t, str_t))) = file_stream.symbol_table() else {
+            println!("Warning: elf {} has no .symtab section", path);
+            return Err(-1);
+        };
         let mut func_vec = sym_t
             .iter()
             .filter(|x| x.st_symtype() == STT_FUNC)
             .enumerate()
-            .map(|(idx, x)| {
-                let func_name = str_t
-                    .get(x.st_name as usize)
-                    .expect("Invalid index of StringTable");
+            .filter_map(|(idx, x)| {
+                // 名字无效的符号直接跳过
+                let func_name = str_t.get(x.st_name as usize).ok()?;
                 let func_start = x.st_value;
                 let func_end = x.st_size + func_start;
 
@@ -67,13 +87,14 @@ impl ElfReader {
                     FunType::LocalFunc
                 };
                 // 似乎end是开区间
-                Func {
+                Some(Func {
                     id: idx as u32,
                     func_type,
                     name: func_name.to_string(),
                     start: func_start,
                     end: func_end,
-                }
+                    sig: None,
+                })
             })
             .filter(|x| x.func_type == FunType::LocalFunc)
             .collect::<Vec<Func>>();
@@ -106,22 +127,83 @@ impl ElfReader {
             );
         });
 
-        let start = func_vec
-            .first()
-            .expect("Failed to get the first elements in func_vec")
-            .start;
-        let end = func_vec
-            .last()
-            .expect("Failed to get the last elements in func_vec")
-            .end;
+        // DWARF中的函数按照起始地址和符号表对应
+        let little_endian = file_stream.ehdr.endianness == AnyEndian::Little;
+        // 不是riscv的ELF（比如测试时的主机程序）按照lp64d处理
+        let float_abi = if file_stream.ehdr.e_machine == EM_RISCV {
+            FloatAbi::from_e_flags(file_stream.ehdr.e_flags)
+        } else {
+            FloatAbi::Double
+        };
+        let sigs = parse_signatures(
+            |name| {
+                let shdr = *file_stream.section_header_by_name(name).ok()??;
+                let (data, chdr) = file_stream.section_data(&shdr).ok()?;
+                // 不支持压缩的调试信息
+                if chdr.is_some() {
+                    return None;
+                }
+                Some(data.to_vec())
+            },
+            little_endian,
+            float_abi,
+        );
+        for func in func_vec.iter_mut() {
+            func.sig = sigs.get(&func.start).cloned();
+        }
+        let cfi = CfiTable::load(
+            |name| {
+                let shdr = *file_stream.section_header_by_name(name).ok()??;
+                // .text只需要地址
+                if name == ".text" {
+                    return Some((shdr.sh_addr, Vec::new()));
+                }
+                let (data, chdr) = file_stream.section_data(&shdr).ok()?;
+                if chdr.is_some() {
+                    return None;
+                }
+                Some((shdr.sh_addr, data.to_vec()))
+            },
+            little_endian,
+        );
+
+        let (Some(first), Some(last)) = (func_vec.first(), func_vec.last()) else {
+            println!("Warning: elf {} has no function", path);
+            return Err(-1);
+        };
+        let start = first.start;
+        let end = last.end;
 
-        ElfReader {
+        Ok(ElfReader {
             id,
             name: name.to_string(),
             start,
             end,
+            asid: None,
             func_vec,
+            cfi,
+        })
+    }
+
+    pub fn with_asid(mut self, asid: Option<u64>) -> Self {
+        self.asid = asid;
+        self
+    }
+
+    // 把所有符号整体平移到base，用于运行时被加载到其它位置的程序
+    pub fn with_base(mut self, base: u64) -> Self {
+        self.start = self.start.wrapping_add(base);
+        self.end = self.end.wrapping_add(base);
+        for func in self.func_vec.iter_mut() {
+            func.start = func.start.wrapping_add(base);
+            func.end = func.end.wrapping_add(base);
         }
+        self.cfi = self.cfi.with_bias(base);
+        self
+    }
+
+    pub fn cfi(&self) -> &CfiTable {
+        &self.cfi
     }
 
     #[cfg(test)]
@@ -137,11 +219,9 @@ impl ElfReader {
             name: name.to_string(),
             start,
             end,
-            func_vec: if let Some(func_vec) = func_vec {
-                func_vec
-            } else {
-                Vec::new()
-            },
+            asid: None,
+            func_vec: func_vec.unwrap_or_default(),
+            cfi: CfiTable::default(),
         }
     }
 
@@ -191,11 +271,86 @@ impl ElfReader {
 
 #[cfg(test)]
 mod tests {
+    use super::super::dwarf::ValueType;
     use super::*;
     use rand::Rng;
 
     fn create_new(id: u32, path: &str) -> ElfReader {
-        ElfReader::new(id, path)
+        ElfReader::new(id, path).expect("Test elf should be loaded")
+    }
+
+    #[test]
+    fn test_reader_invalid() {
+        // 找不到的文件和不是ELF的文件都返回错误
+        assert!(ElfReader::new(0, "./test_elf/not_exist").is_err());
+        assert!(ElfReader::new(0, "./Cargo.toml").is_err());
+    }
+
+    #[inline(never)]
+    fn sig_probe(a: u64, b: u64, c: u64) -> u64 {
+        a.wrapping_mul(b).wrapping_add(c)
+    }
+
+    #[test]
+    fn test_cfi_rule() {
+        assert!(std::hint::black_box(sig_probe)(1, 1, 1) == 2);
+        let path = std::env::current_exe().unwrap();
+        let reader = create_new(0, path.to_str().unwrap());
+        let func = reader
+            .func_vec()
+            .iter()
+            .find(|x| x.name.contains("sig_probe"))
+            .unwrap()
+            .clone();
+        assert!(reader.cfi().rule(func.start).is_some());
+        // 平移之后按照新的地址查找
+        let reader = reader.with_base(0x1000_0000);
+        assert!(reader.cfi().rule(func.start + 0x1000_0000).is_some());
+        assert!(reader.cfi().rule(0).is_none());
+    }
+
+    #[inline(never)]
+    fn typed_probe(a: i32, b: bool, c: *const u8, d: u128) {
+        std::hint::black_box((a, b, c, d));
+    }
+
+    #[test]
+    // 测试程序自身带有调试信息
+    fn test_dwarf_sig() {
+        assert!(std::hint::black_box(sig_probe)(2, 3, 4) == 10);
+        let path = std::env::current_exe().unwrap();
+        let reader = create_new(0, path.to_str().unwrap());
+        let func = reader
+            .func_vec()
+            .iter()
+            .find(|x| x.name.contains("sig_probe"))
+            .unwrap();
+        let sig = func.sig.as_ref().unwrap();
+        assert!(sig.params.len() == 3 && !sig.variadic);
+        assert!(sig.ret == Some(ValueType::Unsigned(8)));
+
+        std::hint::black_box(typed_probe)(-1, true, std::ptr::null(), 1);
+        let func = reader
+            .func_vec()
+            .iter()
+            .find(|x| x.name.contains("typed_probe"))
+            .unwrap();
+        let sig = func.sig.as_ref().unwrap();
+        assert!(
+            sig.params
+                == [
+                    ValueType::Signed(4),
+                    ValueType::Bool,
+                    ValueType::Pointer,
+                    ValueType::Unsigned(16)
+                ]
+        );
+        // 没有返回值的函数
+        assert!(sig.ret.is_none());
+        assert!(
+            sig.format_args(&[0xffff_ffff, 1, 0x1000, 5, 0], &[])
+                == "(a0=-1, a1=true, a2=0x1000, a3:a4=5)"
+        );
     }
 
     #[test]
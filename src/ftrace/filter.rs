@@ -0,0 +1,135 @@
+use regex::Regex;
+use std::collections::HashSet;
+
+// 函数名的匹配规则，以"re:"开头的是正则表达式，其余的按glob处理
+#[derive(Clone, Debug)]
+pub struct NamePattern(Regex);
+
+impl NamePattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        let regex = if let Some(regex) = pattern.strip_prefix("re:") {
+            Regex::new(regex)?
+        } else {
+            Regex::new(&Self::glob_to_regex(pattern))?
+        };
+        Ok(NamePattern(regex))
+    }
+
+    // glob只支持*和?，其余字符都按字面匹配
+    fn glob_to_regex(glob: &str) -> String {
+        let mut regex = String::from("^");
+        for ch in glob.chars() {
+            match ch {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                _ => regex.push_str(&regex::escape(&ch.to_string())),
+            }
+        }
+        regex.push('$');
+        regex
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+}
+
+// 被过滤掉的函数依然在影子栈上，只是不出现在日志和输出里，
+// 它们的时间算到最近的可见的父函数上
+#[derive(Clone, Default)]
+pub struct FuncFilter {
+    include_funcs: Vec<NamePattern>,
+    exclude_funcs: Vec<NamePattern>,
+    include_readers: HashSet<String>,
+    exclude_readers: HashSet<String>,
+    // 栈深度大于它的函数都被过滤，栈底的函数深度为1
+    max_depth: Option<usize>,
+}
+
+impl FuncFilter {
+    pub fn add_func(&mut self, pattern: NamePattern, include: bool) {
+        if include {
+            self.include_funcs.push(pattern);
+        } else {
+            self.exclude_funcs.push(pattern);
+        }
+    }
+
+    pub fn add_reader(&mut self, reader: String, include: bool) {
+        if include {
+            self.include_readers.insert(reader);
+        } else {
+            self.exclude_readers.insert(reader);
+        }
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
+    }
+
+    // 没有任何规则的时候不需要去查函数名
+    pub fn is_empty(&self) -> bool {
+        self.include_funcs.is_empty()
+            && self.exclude_funcs.is_empty()
+            && self.include_readers.is_empty()
+            && self.exclude_readers.is_empty()
+            && self.max_depth.is_none()
+    }
+
+    pub fn is_visible(&self, reader: &str, func: &str, depth: usize) -> bool {
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return false;
+        }
+        if !self.include_readers.is_empty() && !self.include_readers.contains(reader) {
+            return false;
+        }
+        if self.exclude_readers.contains(reader) {
+            return false;
+        }
+        if !self.include_funcs.is_empty() && !self.include_funcs.iter().any(|x| x.is_match(func)) {
+            return false;
+        }
+        !self.exclude_funcs.iter().any(|x| x.is_match(func))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        let glob = NamePattern::new("str*").unwrap();
+        assert!(glob.is_match("strlen"));
+        assert!(!glob.is_match("memstr"));
+        let glob = NamePattern::new("put?").unwrap();
+        assert!(glob.is_match("putc"));
+        assert!(!glob.is_match("putch"));
+        let glob = NamePattern::new("a.b").unwrap();
+        assert!(!glob.is_match("axb"));
+        let regex = NamePattern::new("re:^mem(set|cpy)$").unwrap();
+        assert!(regex.is_match("memset"));
+        assert!(!regex.is_match("memmove"));
+        assert!(NamePattern::new("re:(").is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = FuncFilter::default();
+        assert!(filter.is_empty());
+        filter.add_func(NamePattern::new("mem*").unwrap(), false);
+        filter.add_reader("klib".to_string(), false);
+        filter.set_max_depth(Some(3));
+        assert!(filter.is_visible("nanos", "main", 1));
+        assert!(!filter.is_visible("nanos", "memset", 1));
+        assert!(!filter.is_visible("klib", "putch", 1));
+        assert!(!filter.is_visible("nanos", "main", 4));
+
+        let mut filter = FuncFilter::default();
+        filter.add_func(NamePattern::new("sys_*").unwrap(), true);
+        filter.add_reader("nanos".to_string(), true);
+        assert!(filter.is_visible("nanos", "sys_write", 10));
+        assert!(!filter.is_visible("nanos", "do_syscall", 1));
+        assert!(!filter.is_visible("pal", "sys_write", 1));
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+
+// 一次调用，parent是调用者在同一个数组中的位置，调用者已经被丢弃时为None
+pub struct FoldedFrame {
+    pub parent: Option<usize>,
+    pub name: String,
+    pub duration: u64,
+}
+
+// 把调用折叠成main;loader;memcpy形式的调用链，权重是调用链末端函数的自身时间之和
+pub fn fold(frames: &[FoldedFrame]) -> BTreeMap<String, u64> {
+    let mut child_time = vec![0_u64; frames.len()];
+    for frame in frames.iter() {
+        if let Some(parent) = frame.parent {
+            child_time[parent] += frame.duration;
+        }
+    }
+    let mut paths: Vec<Option<String>> = vec![None; frames.len()];
+    let mut res = BTreeMap::new();
+    for idx in 0..frames.len() {
+        let weight = frames[idx].duration.saturating_sub(child_time[idx]);
+        if weight == 0 {
+            continue;
+        }
+        let path = path(frames, &mut paths, idx);
+        *res.entry(path).or_insert(0) += weight;
+    }
+    res
+}
+
+// 从idx一直向上找到已经计算过的祖先，再依次向下拼接
+fn path(frames: &[FoldedFrame], paths: &mut [Option<String>], idx: usize) -> String {
+    let mut chain = vec![idx];
+    while let Some(parent) = frames[*chain.last().unwrap()].parent {
+        if paths[parent].is_some() {
+            break;
+        }
+        chain.push(parent);
+    }
+    for &cur in chain.iter().rev() {
+        // 分号是调用链的分隔符
+        let name = frames[cur].name.replace(';', ":");
+        let path = match frames[cur].parent.and_then(|x| paths[x].as_ref()) {
+            Some(prefix) => format!("{};{}", prefix, name),
+            None => name,
+        };
+        paths[cur] = Some(path);
+    }
+    paths[idx].clone().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(parent: Option<usize>, name: &str, duration: u64) -> FoldedFrame {
+        FoldedFrame {
+            parent,
+            name: name.to_string(),
+            duration,
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let frames = [
+            frame(None, "main", 100),
+            frame(Some(0), "loader", 60),
+            frame(Some(1), "memcpy", 20),
+            frame(Some(1), "memcpy", 10),
+            frame(Some(0), "memcpy", 15),
+            // 完全被子函数占满的调用不输出
+            frame(Some(4), "a;b", 15),
+        ];
+        let folded = fold(&frames);
+        let lines = folded
+            .iter()
+            .map(|(path, weight)| format!("{} {}", path, weight))
+            .collect::<Vec<_>>();
+        assert!(
+            lines
+                == [
+                    "main 25",
+                    "main;loader 30",
+                    "main;loader;memcpy 30",
+                    "main;memcpy;a:b 15",
+                ]
+        );
+    }
+}
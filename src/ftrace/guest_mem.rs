@@ -0,0 +1,149 @@
+use std::cell::Cell;
+use std::sync::Arc;
+
+// 从模拟器读取客户机内存，返回实际读取的字节数，失败时返回None
+pub type MemReadFn = Arc<dyn Fn(u64, &mut [u8]) -> Option<usize> + Send + Sync>;
+
+thread_local! {
+    // 读内存的回调是在持有manager的锁时调用的，回调中不能再调用ftrace的接口
+    static IN_MEM_READ: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn in_mem_read() -> bool {
+    IN_MEM_READ.with(|x| x.get())
+}
+
+// 每次向模拟器读取的字节数
+const CHUNK_SIZE: usize = 16;
+
+#[derive(Clone)]
+pub struct GuestMem {
+    read: MemReadFn,
+}
+
+impl GuestMem {
+    pub fn new(read: MemReadFn) -> Self {
+        GuestMem { read }
+    }
+
+    fn call_read(&self, addr: u64, buf: &mut [u8]) -> Option<usize> {
+        let prev = IN_MEM_READ.with(|x| x.replace(true));
+        let res = (self.read)(addr, buf);
+        IN_MEM_READ.with(|x| x.set(prev));
+        res
+    }
+
+    // 读满buf才算成功
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buf.len() {
+            match self.call_read(addr.wrapping_add(done as u64), &mut buf[done..]) {
+                Some(n) if n > 0 => done += n.min(buf.len() - done),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    // riscv是小端的
+    pub fn read_u64(&self, addr: u64) -> Option<u64> {
+        let mut buf = [0; 8];
+        self.read(addr, &mut buf).then(|| u64::from_le_bytes(buf))
+    }
+
+    // 读取以0结尾的字符串，最多cap个字节，超过的部分用...表示
+    // 返回的字符串带有引号和转义，可以直接输出
+    pub fn read_c_string(&self, addr: u64, cap: usize) -> Option<String> {
+        let (bytes, terminated) = self.read_c_bytes(addr, cap)?;
+        let text = format!("{:?}", String::from_utf8_lossy(&bytes));
+        if terminated {
+            Some(text)
+        } else {
+            Some(text + "...")
+        }
+    }
+
+    // 读取以0结尾的字符串，不加引号，超过cap的部分直接截断
+    pub fn read_label(&self, addr: u64, cap: usize) -> Option<String> {
+        let (bytes, _) = self.read_c_bytes(addr, cap)?;
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    // 返回读到的字节以及是否遇到了结尾的0
+    fn read_c_bytes(&self, addr: u64, cap: usize) -> Option<(Vec<u8>, bool)> {
+        let mut bytes = Vec::new();
+        let mut terminated = false;
+        while bytes.len() < cap && !terminated {
+            let mut chunk = [0; CHUNK_SIZE];
+            let len = CHUNK_SIZE.min(cap - bytes.len());
+            let n = match self.call_read(addr.wrapping_add(bytes.len() as u64), &mut chunk[..len]) {
+                Some(n) if n > 0 => n.min(len),
+                // 一个字节都读不到说明地址无效
+                _ if bytes.is_empty() => return None,
+                _ => break,
+            };
+            match chunk[..n].iter().position(|&x| x == 0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&chunk[..end]);
+                    terminated = true;
+                }
+                None => bytes.extend_from_slice(&chunk[..n]),
+            }
+        }
+        Some((bytes, terminated))
+    }
+
+    // 用一段连续的内存模拟客户机，每次最多读取max_read个字节
+    #[cfg(test)]
+    pub fn dummy(base: u64, data: Vec<u8>, max_read: usize) -> GuestMem {
+        GuestMem::new(Arc::new(move |addr, buf: &mut [u8]| {
+            let offset = addr.checked_sub(base)? as usize;
+            let remain = data.get(offset..)?;
+            let n = buf.len().min(remain.len()).min(max_read);
+            buf[..n].copy_from_slice(&remain[..n]);
+            Some(n)
+        }))
+    }
+
+    // 栈上从0x7000开始的一段内存，words是每个地址上保存的64位值
+    #[cfg(test)]
+    pub fn dummy_stack(words: &[(u64, u64)]) -> GuestMem {
+        let mut data = vec![0; 0x1000];
+        for (addr, value) in words {
+            let offset = (addr - 0x7000) as usize;
+            data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        GuestMem::dummy(0x7000, data, 8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let mut data = b"hello\0".to_vec();
+        data.extend_from_slice(&0x8000_1234_u64.to_le_bytes());
+        let mem = GuestMem::dummy(0x1000, data, 3);
+        assert!(mem.read_c_string(0x1000, 64).as_deref() == Some("\"hello\""));
+        assert!(mem.read_c_string(0x1000, 4).as_deref() == Some("\"hell\"..."));
+        assert!(mem.read_label(0x1000, 64).as_deref() == Some("hello"));
+        // 分多次读取
+        assert!(mem.read_u64(0x1006) == Some(0x8000_1234));
+        // 越界的部分读不到
+        assert!(mem.read_u64(0x1008).is_none());
+        assert!(mem.read_c_string(0x2000, 64).is_none());
+
+        let mem = GuestMem::dummy(0x1000, b"a\nb".to_vec(), 16);
+        // 没有结尾的0时读到内存的末尾为止
+        assert!(mem.read_c_string(0x1000, 64).as_deref() == Some("\"a\\nb\"..."));
+        // 只有回调执行期间才处于读内存的状态
+        let mem = GuestMem::new(Arc::new(|_, buf: &mut [u8]| {
+            buf.fill(in_mem_read() as u8);
+            Some(buf.len())
+        }));
+        assert!(mem.read_u64(0) == Some(0x0101_0101_0101_0101));
+        assert!(!in_mem_read());
+    }
+}
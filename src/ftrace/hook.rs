@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+// 传给回调的函数信息，只在回调期间有效
+pub struct HookInfo<'a> {
+    pub hart: u32,
+    pub ctx: u64,
+    pub name: &'a str,
+    pub reader: &'a str,
+    // 函数的起始地址，未知函数为0
+    pub addr: u64,
+    // 按函数参数个数截取的a0到a7，没有记录寄存器的时候为None
+    pub args: Option<&'a [u64]>,
+    // 只有正常返回的时候才有返回值
+    pub ret_val: Option<(u64, Option<u64>)>,
+}
+
+// 返回true表示请求模拟器停下来，所有hart共享同一个回调，所以要求Send + Sync
+pub type HookFn = Arc<dyn Fn(&HookInfo) -> bool + Send + Sync>;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum HookTarget {
+    Name(String),
+    Addr(u64),
+}
+
+impl HookTarget {
+    // 以0x开头的按函数起始地址匹配，其余的按函数名匹配
+    pub fn parse(target: &str) -> Self {
+        target
+            .strip_prefix("0x")
+            .or_else(|| target.strip_prefix("0X"))
+            .and_then(|x| u64::from_str_radix(x, 16).ok())
+            .map(HookTarget::Addr)
+            .unwrap_or_else(|| HookTarget::Name(target.to_string()))
+    }
+
+    pub fn is_match(&self, name: &str, addr: u64) -> bool {
+        match self {
+            HookTarget::Name(x) => x == name,
+            HookTarget::Addr(x) => *x == addr && addr != 0,
+        }
+    }
+}
+
+// 已经匹配但还没有执行的回调。manager只负责记录，调用者释放manager的锁之后再执行，
+// 这样回调中可以再调用ftrace的接口
+pub struct PendingHook {
+    pub hooks: Vec<HookFn>,
+    pub hart: u32,
+    pub ctx: u64,
+    pub name: String,
+    pub reader: String,
+    pub addr: u64,
+    pub args: Option<Vec<u64>>,
+    pub ret_val: Option<(u64, Option<u64>)>,
+}
+
+impl PendingHook {
+    // 每个回调都要执行，任意一个请求停止就返回true
+    pub fn run(&self) -> bool {
+        let info = HookInfo {
+            hart: self.hart,
+            ctx: self.ctx,
+            name: &self.name,
+            reader: &self.reader,
+            addr: self.addr,
+            args: self.args.as_deref(),
+            ret_val: self.ret_val,
+        };
+        self.hooks
+            .iter()
+            .fold(false, |stop, hook| hook(&info) | stop)
+    }
+}
+
+// 按照触发的顺序执行
+pub fn run_pending(pending: Vec<PendingHook>) -> bool {
+    pending.iter().fold(false, |stop, hook| hook.run() | stop)
+}
+
+#[derive(Clone)]
+pub struct FuncHook {
+    pub target: HookTarget,
+    pub on_enter: Option<HookFn>,
+    pub on_exit: Option<HookFn>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        assert!(HookTarget::parse("panic") == HookTarget::Name("panic".to_string()));
+        assert!(HookTarget::parse("0x80000000") == HookTarget::Addr(0x8000_0000));
+        // 不是合法的十六进制数时按名字处理
+        assert!(HookTarget::parse("0xzz") == HookTarget::Name("0xzz".to_string()));
+        assert!(HookTarget::parse("loader").is_match("loader", 0x1000));
+        assert!(HookTarget::parse("0x1000").is_match("loader", 0x1000));
+        assert!(!HookTarget::parse("0x0").is_match("unknown", 0));
+    }
+}
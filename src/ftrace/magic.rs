@@ -0,0 +1,112 @@
+// 客户机程序通过魔数指令控制ftrace，a0是标记的名字的地址
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MagicOp {
+    TraceOn,
+    TraceOff,
+    Mark,
+    RegionBegin,
+    RegionEnd,
+    DumpStack,
+}
+
+impl MagicOp {
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            0 => Some(MagicOp::TraceOn),
+            1 => Some(MagicOp::TraceOff),
+            2 => Some(MagicOp::Mark),
+            3 => Some(MagicOp::RegionBegin),
+            4 => Some(MagicOp::RegionEnd),
+            5 => Some(MagicOp::DumpStack),
+            _ => None,
+        }
+    }
+}
+
+// 操作编号的个数，hint_base+MAGIC_OP_NUM不能超过shamt的范围
+pub const MAGIC_OP_NUM: u32 = 6;
+
+const ECALL: u32 = 0x0000_0073;
+// slli x0, x0, 0，rd为x0的slli是HINT指令，编译器不会生成
+const SLLI_X0: u32 = 0x0000_1013;
+// rv64的shamt有6位
+pub const SHAMT_NUM: u32 = 64;
+const SHAMT_MASK: u32 = (SHAMT_NUM - 1) << 20;
+
+pub const DEFAULT_DUMP_PATH: &str = "./ftrace_stack.txt";
+
+#[derive(Clone, Debug)]
+pub struct MagicConfig {
+    // slli x0, x0, hint_base+操作编号
+    pub hint_base: Option<u32>,
+    // a7等于ecall_num的ecall，操作编号放在a1
+    pub ecall_num: Option<u64>,
+    // DumpStack写入的文件
+    pub dump_path: String,
+}
+
+impl Default for MagicConfig {
+    fn default() -> Self {
+        MagicConfig {
+            hint_base: None,
+            ecall_num: None,
+            dump_path: DEFAULT_DUMP_PATH.to_string(),
+        }
+    }
+}
+
+impl MagicConfig {
+    pub fn decode(&self, inst: u32, regs: &[u64]) -> Option<MagicOp> {
+        if inst == ECALL {
+            let num = self.ecall_num?;
+            if regs.get(17) != Some(&num) {
+                return None;
+            }
+            MagicOp::from_code(*regs.get(11)?)
+        } else if inst & !SHAMT_MASK == SLLI_X0 {
+            let shamt = (inst & SHAMT_MASK) >> 20;
+            MagicOp::from_code(shamt.checked_sub(self.hint_base?)? as u64)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slli_x0(shamt: u32) -> u32 {
+        SLLI_X0 | (shamt << 20)
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut regs = [0; 32];
+        let config = MagicConfig::default();
+        // 默认不识别任何魔数指令
+        assert!(config.decode(slli_x0(0x20), &regs).is_none());
+        assert!(config.decode(ECALL, &regs).is_none());
+
+        let config = MagicConfig {
+            hint_base: Some(0x20),
+            ecall_num: Some(0x5446),
+            ..Default::default()
+        };
+        assert!(config.decode(slli_x0(0x20), &regs) == Some(MagicOp::TraceOn));
+        assert!(config.decode(slli_x0(0x25), &regs) == Some(MagicOp::DumpStack));
+        assert!(config.decode(slli_x0(0x1f), &regs).is_none());
+        assert!(config.decode(slli_x0(0x26), &regs).is_none());
+        // slli x1, x0, 0x22 不是HINT
+        assert!(config.decode(slli_x0(0x22) | (1 << 7), &regs).is_none());
+
+        // 其它系统调用不受影响
+        regs[17] = 64;
+        regs[11] = 2;
+        assert!(config.decode(ECALL, &regs).is_none());
+        regs[17] = 0x5446;
+        assert!(config.decode(ECALL, &regs) == Some(MagicOp::Mark));
+        regs[11] = 9;
+        assert!(config.decode(ECALL, &regs).is_none());
+    }
+}
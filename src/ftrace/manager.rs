@@ -1,11 +1,24 @@
+use super::call_graph::CallGraph;
+use super::clock::*;
+use super::dwarf::{FuncSig, MAX_REG_ARGS};
 use super::elf_reader::*;
+use super::filter::FuncFilter;
+use super::guest_mem::GuestMem;
+use super::hook::{FuncHook, HookFn, PendingHook};
+use super::magic::{MagicConfig, MagicOp};
+use super::marker::{Marker, MarkerKind};
+use super::profile::Profiler;
+use super::sink::{EventKind, SharedSink, TraceEvent};
+use super::unwind::{unwind, BtFrame};
 use crate::debug_println;
 use core::panic;
+use std::borrow::Cow;
 use std::cell::Cell;
 use std::cell::{Ref, RefCell};
 use std::cmp::Ordering;
-use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
 
 #[derive(PartialEq, Eq, Clone, Copy, Hash)]
 pub enum CurReader {
@@ -13,6 +26,46 @@ pub enum CurReader {
     ProgReaders(usize),
 }
 
+// 用于把同一个函数的所有实例聚合在一起
+#[derive(PartialEq, Eq, Clone, Copy, Hash)]
+pub struct FuncKey {
+    pub reader: Option<CurReader>,
+    pub func_type: FunType,
+    pub id: u32,
+}
+
+// trace_log的容量限制，超出限制时丢弃最早的记录，func_stack不受影响
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum LogLimit {
+    #[default]
+    Unbounded,
+    // 只保留最近的N条记录
+    Events(usize),
+    // 只保留最近N个时间单位内的记录，单位由时钟决定
+    Window(u64),
+    // 不在内存中保留记录，只通过sink输出，适合长时间运行
+    Disabled,
+}
+
+// ret找不到合理的返回目标时的处理方式
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum DesyncPolicy {
+    // 记录下来，从返回的目标重建栈，然后继续运行
+    #[default]
+    Recover,
+    // 直接panic，用于调试ftrace本身
+    Strict,
+}
+
+// 调用发生时的现场，用于在ret的时候精确匹配栈帧
+#[derive(PartialEq, Eq, Clone, Copy, Default)]
+pub struct CallSite {
+    // 调用时的sp，栈向下增长，所以当前sp大于它时说明这个栈帧已经失效
+    pub sp: Option<u64>,
+    // 调用指令的下一条指令，也就是正常返回时的pc
+    pub ret_addr: Option<u64>,
+}
+
 pub struct FuncInstance {
     // 这里instance的id主要是用于结合cur_reader定位函数信息位置的
     id: u32,
@@ -20,6 +73,30 @@ pub struct FuncInstance {
     func_type: FunType,
     ret_val: Cell<Option<(u64, Option<u64>)>>,
     paras: RefCell<Option<Vec<u64>>>,
+    // 调用时读取的字符串参数，按照寄存器编号索引
+    strings: RefCell<Vec<(usize, String)>>,
+    // 弹栈时格式化好的参数列表，弹栈之后寄存器就被释放了
+    args_text: RefCell<Option<String>>,
+    call_site: CallSite,
+    // 是否是longjmp之类的非局部跳转函数
+    nonlocal_exit: bool,
+    // 是否是被sp回溯弹出的，而不是正常返回的
+    unwound: Cell<bool>,
+    // 压栈时的栈顶，也就是调用者。用Weak避免很深的调用链在释放时递归，
+    // 调用者在栈上时一定存活，被弹出并且移出trace_log之后就不再保留
+    parent: Weak<FuncInstance>,
+    // 最近的没有被过滤的祖先，压栈时确定，隐藏的祖先释放之后仍然能找到它
+    visible_parent: Weak<FuncInstance>,
+    // 所属的上下文（进程）
+    ctx: u64,
+    // 已经结束的子函数的总时间，用于计算自身时间
+    child_time: Cell<u64>,
+    // 被过滤掉，不出现在日志和输出里
+    hidden: bool,
+    // attach时从客户机栈上恢复的栈帧，开始时间和调用都不是真实的
+    seeded: bool,
+    // 调用事件已经输出到sink，结束时也要输出对应的事件
+    emitted: Cell<bool>,
     _start_time: u64,
     _end_time: Cell<u64>,
 }
@@ -30,6 +107,7 @@ impl FuncInstance {
         func_type: FunType,
         reader: CurReader,
         _start_time: u64,
+        call_site: CallSite,
         paras: Option<&Vec<u64>>,
     ) -> Self {
         FuncInstance {
@@ -38,12 +116,29 @@ impl FuncInstance {
             func_type,
             ret_val: Cell::new(None),
             paras: RefCell::new(paras.cloned()),
+            strings: RefCell::new(Vec::new()),
+            args_text: RefCell::new(None),
+            call_site,
+            nonlocal_exit: false,
+            unwound: Cell::new(false),
+            parent: Weak::new(),
+            visible_parent: Weak::new(),
+            ctx: 0,
+            child_time: Cell::new(0),
+            hidden: false,
+            seeded: false,
+            emitted: Cell::new(false),
             _start_time,
             _end_time: Cell::new(_start_time),
         }
     }
 
-    fn new_with_nullreader(id: u32, _start_time: u64, paras: Option<&Vec<u64>>) -> Self {
+    fn new_with_nullreader(
+        id: u32,
+        _start_time: u64,
+        call_site: CallSite,
+        paras: Option<&Vec<u64>>,
+    ) -> Self {
         // 没有reader的函数一定时external的
         FuncInstance {
             id,
@@ -51,11 +146,55 @@ impl FuncInstance {
             func_type: FunType::ExternalFunc,
             ret_val: Cell::new(None),
             paras: RefCell::new(paras.cloned()),
+            strings: RefCell::new(Vec::new()),
+            args_text: RefCell::new(None),
+            call_site,
+            nonlocal_exit: false,
+            unwound: Cell::new(false),
+            parent: Weak::new(),
+            visible_parent: Weak::new(),
+            ctx: 0,
+            child_time: Cell::new(0),
+            hidden: false,
+            seeded: false,
+            emitted: Cell::new(false),
             _start_time,
             _end_time: Cell::new(_start_time),
         }
     }
 
+    fn with_parent(mut self, parent: Option<&Rc<FuncInstance>>) -> Self {
+        if let Some(parent) = parent {
+            self.parent = Rc::downgrade(parent);
+            self.visible_parent = if parent.hidden {
+                parent.visible_parent.clone()
+            } else {
+                Rc::downgrade(parent)
+            };
+        }
+        self
+    }
+
+    fn with_ctx(mut self, ctx: u64) -> Self {
+        self.ctx = ctx;
+        self
+    }
+
+    fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    fn with_seeded(mut self, seeded: bool) -> Self {
+        self.seeded = seeded;
+        self
+    }
+
+    fn with_nonlocal_exit(mut self, nonlocal_exit: bool) -> Self {
+        self.nonlocal_exit = nonlocal_exit;
+        self
+    }
+
     fn set_end_time(&self, end_time: u64) {
         self._end_time.set(end_time)
     }
@@ -89,12 +228,29 @@ impl FuncInstance {
     pub fn func_type(&self) -> FunType {
         self.func_type
     }
+
+    #[allow(dead_code)]
+    pub fn parent(&self) -> Option<Rc<FuncInstance>> {
+        self.parent.upgrade()
+    }
+
+    pub fn ctx(&self) -> u64 {
+        self.ctx
+    }
+
+    pub fn key(&self) -> FuncKey {
+        FuncKey {
+            reader: self.reader,
+            func_type: self.func_type,
+            id: self.id,
+        }
+    }
     #[allow(dead_code)]
     pub fn ret_val(&self) -> Option<(u64, Option<u64>)> {
         self.ret_val.get()
     }
     #[allow(dead_code)]
-    pub fn paras(&self) -> Ref<Option<Vec<u64>>> {
+    pub fn paras(&self) -> Ref<'_, Option<Vec<u64>>> {
         self.paras.borrow()
     }
 
@@ -103,6 +259,19 @@ impl FuncInstance {
         *paras_ = paras;
     }
 
+    pub fn unwound(&self) -> bool {
+        self.unwound.get()
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    // 最近的没有被过滤的祖先
+    pub fn visible_parent(&self) -> Option<Rc<FuncInstance>> {
+        self.visible_parent.upgrade()
+    }
+
     pub fn _start_time(&self) -> u64 {
         self._start_time
     }
@@ -112,20 +281,106 @@ impl FuncInstance {
     }
 }
 
+// 切换出去的上下文的影子栈以及相关的状态
+struct ContextState {
+    func_stack: Vec<Rc<FuncInstance>>,
+    cur_func: Option<Rc<FuncInstance>>,
+    cur_reader: CurReader,
+    cur_sp: Option<u64>,
+}
+
+impl Default for ContextState {
+    fn default() -> Self {
+        ContextState {
+            func_stack: Vec::new(),
+            cur_func: None,
+            cur_reader: CurReader::MainReader,
+            cur_sp: None,
+        }
+    }
+}
+
 pub struct Manager {
+    // 每个hart有一个独立的manager
+    hart: u32,
     show_context: bool,
-    main_reader: ElfReader,
+    // reader只读，在多个hart的manager之间共享
+    main_reader: Arc<ElfReader>,
     cur_reader: CurReader,
-    prog_readers: Option<Vec<ElfReader>>,
-    trace_log: Vec<Rc<FuncInstance>>,
-    time_base: Vec<u64>,
+    prog_readers: Option<Vec<Arc<ElfReader>>>,
+    // 已经卸载的程序的reader id
+    unloaded_readers: HashSet<u32>,
+    trace_log: VecDeque<Rc<FuncInstance>>,
+    time_base: VecDeque<u64>,
+    // 用户插入的标记，按照插入的顺序排列
+    markers: VecDeque<Marker>,
+    // 还没有结束的区间的名字，以及开始标记是否输出到了sink
+    open_regions: Vec<(String, bool)>,
+    log_limit: LogLimit,
+    // 因为容量限制被丢弃的记录数
+    dropped_events: u64,
     func_stack: Vec<Rc<FuncInstance>>,
-    init_time: u64,
+    // 当前上下文最近一次进入的函数，也就是正在运行的函数
+    cur_func: Option<Rc<FuncInstance>>,
+    // 当前的上下文编号，以及切换出去的上下文
+    ctx: u64,
+    contexts: HashMap<u64, ContextState>,
+    // time_base和函数的起止时间都使用这个时钟
+    clock: Clock,
+    // 最近一次同步的sp，新的栈帧会记录它
+    cur_sp: Option<u64>,
+    // 当前正在处理的调用指令的返回地址
+    cur_ret_addr: Option<u64>,
+    // sync_sp刚刚弹出过栈帧，下一次ret可能直接回到栈顶的函数
+    sp_unwound: bool,
+    // 会跳出当前栈的函数，比如longjmp，遇到它们时ret需要重新同步栈
+    nonlocal_exits: HashSet<String>,
+    // 用户提供的函数签名，没有DWARF信息时使用
+    signatures: HashMap<String, FuncSig>,
+    // 读取客户机内存的回调，用于读取字符串参数
+    guest_mem: Option<GuestMem>,
+    // 字符串参数最多读取的字节数
+    string_cap: usize,
+    // 决定哪些函数出现在日志和输出里
+    filter: FuncFilter,
+    // 已经结束的函数实例的统计
+    profiler: Profiler,
+    // 已经结束的调用的调用关系
+    call_graph: CallGraph,
+    // 压栈和弹栈时实时写出事件，多个hart共享
+    sinks: Vec<SharedSink>,
+    // 进入和离开函数时的回调
+    hooks: Vec<FuncHook>,
+    // 正在处理的ret的返回值，交给第一个弹出的栈帧
+    cur_ret_val: Option<(u64, Option<u64>)>,
+    // 已经触发但还没有执行的回调
+    pending_hooks: Vec<PendingHook>,
+    // 客户机程序控制ftrace用的魔数指令
+    magic: MagicConfig,
+    // 暂停时只停止记录trace_log和输出事件，影子栈、统计和回调照常
+    paused: bool,
+    // 正在attach，新的栈帧都是从客户机栈上恢复的
+    seeding: bool,
+    desync_policy: DesyncPolicy,
+    // 从失去同步中恢复的次数
+    desync_count: u64,
 }
 
+pub const DEFAULT_STRING_CAP: usize = 64;
+
+pub const DEFAULT_NONLOCAL_EXITS: [&str; 7] = [
+    "longjmp",
+    "_longjmp",
+    "siglongjmp",
+    "__longjmp_chk",
+    "_Unwind_RaiseException",
+    "_Unwind_Resume",
+    "yield",
+];
+
 impl Manager {
     // 这里依靠reader保证start一定小于等于end
-    // 同时这里一定是有序(start有序）的序列
+    // 不同地址空间的reader可以占用同一段地址，只检查可能同时可见的reader
     // 如果有重叠返回true，否则返回false
     fn check_reader_overlap(main_readers: &ElfReader, readers: Option<Vec<&ElfReader>>) -> bool {
         if readers.is_none() {
@@ -134,9 +389,10 @@ impl Manager {
             let mut res = false;
             for (i, &item) in x.iter().enumerate() {
                 assert!(item.start <= item.end);
-                let next = x.get(i + 1);
-                if let Some(&next) = next {
-                    res = res || (item.end > next.start);
+                for &next in x.iter().skip(i + 1) {
+                    if Self::share_address_space(item, next) {
+                        res = res || (item.start < next.end && next.start < item.end);
+                    }
                 }
             }
 
@@ -149,14 +405,26 @@ impl Manager {
         }
     }
 
-    pub fn new(show_context: bool, main_path: &str, progs_path: Option<Vec<&str>>) -> Self {
-        let main_reader = ElfReader::new(0, main_path);
+    fn share_address_space(a: &ElfReader, b: &ElfReader) -> bool {
+        a.asid.is_none() || b.asid.is_none() || a.asid == b.asid
+    }
+
+    pub fn new(
+        show_context: bool,
+        main_path: &str,
+        progs_path: Option<Vec<&str>>,
+    ) -> Result<Self, isize> {
+        let main_reader = ElfReader::new(0, main_path)?;
         let prog_readers = if let Some(x) = progs_path {
             let mut prog_readers: Vec<ElfReader> = Vec::new();
             for (idx, i) in x.into_iter().enumerate() {
-                prog_readers.push(ElfReader::new((idx + 1) as u32, i));
+                prog_readers.push(ElfReader::new((idx + 1) as u32, i)?);
+            }
+            prog_readers.sort_by_key(|a| a.start);
+            // 排序之后需要重新分配id，保证id和在vec中的位置对应
+            for (idx, i) in prog_readers.iter_mut().enumerate() {
+                i.id = (idx + 1) as u32;
             }
-            prog_readers.sort_by(|a, b| a.start.cmp(&b.start));
             for i in &prog_readers {
                 debug_println!("Progs elf reader: name {}, id {}", i.name, i.id);
             }
@@ -164,33 +432,205 @@ impl Manager {
         } else {
             None
         };
+        Ok(Self::from_readers(show_context, main_reader, prog_readers))
+    }
 
-        let init_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
-
+    fn from_readers(
+        show_context: bool,
+        main_reader: ElfReader,
+        prog_readers: Option<Vec<ElfReader>>,
+    ) -> Self {
         let prog_readers_ref = prog_readers.as_ref().map(|x| x.iter().collect());
-        Self::check_reader_overlap(&main_reader, prog_readers_ref);
+        if Self::check_reader_overlap(&main_reader, prog_readers_ref) {
+            println!("Warning: elf readers overlap in the same address space!");
+        }
+        let main_reader = Arc::new(main_reader);
+        let prog_readers = prog_readers.map(|x| x.into_iter().map(Arc::new).collect());
 
         Manager {
+            hart: 0,
             show_context,
             main_reader,
             cur_reader: CurReader::MainReader,
             prog_readers,
-            trace_log: Vec::new(),
-            time_base: Vec::new(),
+            unloaded_readers: HashSet::new(),
+            trace_log: VecDeque::new(),
+            time_base: VecDeque::new(),
+            markers: VecDeque::new(),
+            open_regions: Vec::new(),
+            log_limit: LogLimit::Unbounded,
+            dropped_events: 0,
+            func_stack: Vec::new(),
+            cur_func: None,
+            ctx: 0,
+            contexts: HashMap::new(),
+            clock: Clock::new(ClockType::WallMillis),
+            cur_sp: None,
+            cur_ret_addr: None,
+            sp_unwound: false,
+            nonlocal_exits: DEFAULT_NONLOCAL_EXITS
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            signatures: HashMap::new(),
+            guest_mem: None,
+            string_cap: DEFAULT_STRING_CAP,
+            filter: FuncFilter::default(),
+            profiler: Profiler::default(),
+            call_graph: CallGraph::default(),
+            sinks: Vec::new(),
+            hooks: Vec::new(),
+            cur_ret_val: None,
+            pending_hooks: Vec::new(),
+            magic: MagicConfig::default(),
+            paused: false,
+            seeding: false,
+            desync_policy: DesyncPolicy::default(),
+            desync_count: 0,
+        }
+    }
+
+    // 为另一个hart创建manager，共享reader和配置，但是栈和日志都是独立的
+    pub fn fork_hart(&self, hart: u32) -> Self {
+        Manager {
+            hart,
+            show_context: self.show_context,
+            main_reader: self.main_reader.clone(),
+            cur_reader: CurReader::MainReader,
+            prog_readers: self.prog_readers.clone(),
+            unloaded_readers: self.unloaded_readers.clone(),
+            trace_log: VecDeque::new(),
+            time_base: VecDeque::new(),
+            markers: VecDeque::new(),
+            open_regions: Vec::new(),
+            log_limit: self.log_limit,
+            dropped_events: 0,
             func_stack: Vec::new(),
-            init_time,
+            cur_func: None,
+            ctx: 0,
+            contexts: HashMap::new(),
+            clock: self.clock.fork(),
+            cur_sp: None,
+            cur_ret_addr: None,
+            sp_unwound: false,
+            nonlocal_exits: self.nonlocal_exits.clone(),
+            signatures: self.signatures.clone(),
+            guest_mem: self.guest_mem.clone(),
+            string_cap: self.string_cap,
+            filter: self.filter.clone(),
+            profiler: Profiler::default(),
+            call_graph: CallGraph::default(),
+            sinks: self.sinks.clone(),
+            hooks: self.hooks.clone(),
+            cur_ret_val: None,
+            pending_hooks: Vec::new(),
+            magic: self.magic.clone(),
+            paused: self.paused,
+            seeding: false,
+            desync_policy: self.desync_policy,
+            desync_count: 0,
+        }
+    }
+
+    pub fn hart(&self) -> u32 {
+        self.hart
+    }
+
+    pub fn set_nonlocal_exits(&mut self, nonlocal_exits: HashSet<String>) {
+        self.nonlocal_exits = nonlocal_exits;
+    }
+
+    fn cur_call_site(&self) -> CallSite {
+        CallSite {
+            sp: self.cur_sp,
+            ret_addr: self.cur_ret_addr,
+        }
+    }
+
+    // 添加一个reader，返回它的id
+    pub fn add_prog_reader(&mut self, mut reader: ElfReader) -> u32 {
+        reader.id = (self.prog_readers.as_ref().map_or(0, |x| x.len()) + 1) as u32;
+        self.push_prog_reader(Arc::new(reader))
+    }
+
+    // 其它hart加载同一个reader时直接共享，id在所有hart上保持一致
+    pub fn push_prog_reader(&mut self, reader: Arc<ElfReader>) -> u32 {
+        let id = reader.id;
+        debug_println!(
+            "Progs elf reader: name {}, id {}, asid {:?}",
+            reader.name,
+            reader.id,
+            reader.asid
+        );
+        let readers = self.prog_readers.get_or_insert_with(Vec::new);
+        assert!(
+            readers.len() + 1 == id as usize,
+            "Reader id is not continuous"
+        );
+        readers.push(reader);
+        // 已经卸载的reader不参与检查，新程序一般就加载在旧程序原来的位置
+        let prog_readers_ref = self.prog_readers.as_ref().map(|x| {
+            x.iter()
+                .filter(|x| !self.unloaded_readers.contains(&x.id))
+                .map(|x| x.as_ref())
+                .collect()
+        });
+        if Self::check_reader_overlap(&self.main_reader, prog_readers_ref) {
+            println!("Warning: elf readers overlap in the same address space!");
+        }
+        id
+    }
+
+    pub fn prog_reader(&self, id: u32) -> Option<&Arc<ElfReader>> {
+        self.prog_readers
+            .as_ref()
+            .and_then(|x| x.get((id as usize).checked_sub(1)?))
+    }
+
+    // 卸载之后的reader不再用于查找新的函数，但是依然保留，
+    // 栈上和日志里引用它的函数实例还需要通过它来找到函数名
+    pub fn unload_prog_reader(&mut self, id: u32) -> Result<(), isize> {
+        if self.prog_reader(id).is_none() || !self.unloaded_readers.insert(id) {
+            println!("Warning: can not unload elf reader {}", id);
+            return Err(-1);
         }
+        if self.cur_reader == CurReader::ProgReaders(id as usize - 1) {
+            self.cur_reader = CurReader::MainReader;
+        }
+        Ok(())
+    }
+
+    // 当前上下文就是当前的地址空间
+    fn reader_visible(&self, reader: &ElfReader) -> bool {
+        reader.asid.is_none_or(|asid| asid == self.ctx)
+            && !self.unloaded_readers.contains(&reader.id)
+    }
+
+    fn is_nonlocal_exit(&self, reader: CurReader, id: u32) -> bool {
+        self.get_reader(&reader)
+            .get_func(id)
+            .is_some_and(|func| self.nonlocal_exits.contains(&func.name))
     }
 
     pub fn get_time(&self) -> u64 {
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64;
-        time - self.init_time
+        self.clock.now()
+    }
+
+    // 需要在第一次跳转之前设置，否则前后的时间不可比较
+    pub fn set_clock_type(&mut self, clock_type: ClockType) {
+        self.clock = Clock::new(clock_type);
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn clock_tick(&mut self) {
+        self.clock.tick();
+    }
+
+    pub fn set_time(&mut self, value: u64) -> Result<(), isize> {
+        self.clock.set(value)
     }
 
     pub fn get_reader(&self, reader: &CurReader) -> &ElfReader {
@@ -210,6 +650,11 @@ impl Manager {
         }
     }
 
+    // 包括主程序和已经卸载的程序
+    pub fn reader_count(&self) -> usize {
+        1 + self.prog_readers.as_ref().map_or(0, |x| x.len())
+    }
+
     pub fn cur_reader(&self) -> &ElfReader {
         self.get_reader(&self.cur_reader)
     }
@@ -232,8 +677,176 @@ impl Manager {
 
     fn trace_log_push(&mut self, elem: Rc<FuncInstance>) {
         // 这是为了保证所有的trace_log被push进入元素的时候都携带一个时间戳
-        self.trace_log.push(elem);
-        self.time_base.push(self.get_time());
+        self.cur_func = Some(elem.clone());
+        if self.paused {
+            return;
+        }
+        // 被过滤的函数记在最近的可见的父函数上
+        let elem = if elem.hidden {
+            match elem.visible_parent() {
+                Some(parent) => parent,
+                None => return,
+            }
+        } else {
+            elem
+        };
+        if self.trace_log.back().is_some_and(|x| Rc::ptr_eq(x, &elem)) {
+            return;
+        }
+        if self.log_limit == LogLimit::Disabled {
+            self.dropped_events += 1;
+            return;
+        }
+        let time = self.get_time();
+        self.trace_log.push_back(elem);
+        self.time_base.push_back(time);
+        self.trim_log(time);
+    }
+
+    // 按照容量限制丢弃最早的记录，栈帧由func_stack持有，所以不会受影响
+    fn trim_log(&mut self, now: u64) {
+        let overflow = match self.log_limit {
+            LogLimit::Unbounded => 0,
+            LogLimit::Disabled => self.trace_log.len(),
+            LogLimit::Events(cap) => self.trace_log.len().saturating_sub(cap),
+            LogLimit::Window(window) => self
+                .time_base
+                .iter()
+                .take_while(|time| now.saturating_sub(**time) > window)
+                .count(),
+        };
+        if overflow > 0 {
+            self.trace_log.drain(..overflow);
+            self.time_base.drain(..overflow);
+            self.dropped_events += overflow as u64;
+            // 位于被丢弃的记录之间的标记也一起丢弃
+            while self
+                .markers
+                .front()
+                .is_some_and(|x| x.pos < self.dropped_events)
+            {
+                self.markers.pop_front();
+            }
+        }
+    }
+
+    pub fn set_log_limit(&mut self, log_limit: LogLimit) {
+        self.log_limit = log_limit;
+        let now = self.get_time();
+        self.trim_log(now);
+        if log_limit == LogLimit::Disabled {
+            self.markers.clear();
+        }
+    }
+
+    // 最近打开的同名区间
+    fn open_region(&self, label: &str) -> Result<usize, isize> {
+        self.open_regions
+            .iter()
+            .rposition(|(x, _)| x == label)
+            .ok_or_else(|| {
+                println!(
+                    "Warning: region {} is not open on hart {}",
+                    label, self.hart
+                );
+                -1
+            })
+    }
+
+    // 检查标记能否添加，但是不修改状态
+    pub fn check_mark(&self, kind: MarkerKind, label: &str) -> Result<(), isize> {
+        if kind == MarkerKind::End {
+            self.open_region(label)?;
+        }
+        Ok(())
+    }
+
+    // 在trace_log当前的位置插入一个标记，区间的结束必须对应一个还没有结束的开始
+    pub fn mark(&mut self, kind: MarkerKind, label: &str) -> Result<(), isize> {
+        // 和调用一样，暂停时不输出到sink，区间的结束跟随开始
+        let emitted = match kind {
+            MarkerKind::Begin => {
+                self.open_regions.push((label.to_string(), !self.paused));
+                !self.paused
+            }
+            MarkerKind::End => {
+                let idx = self.open_region(label)?;
+                self.open_regions.remove(idx).1
+            }
+            MarkerKind::Instant => !self.paused,
+        };
+        let marker = Marker {
+            kind,
+            label: label.to_string(),
+            time: self.get_time(),
+            ctx: self.ctx,
+            depth: self.func_stack.len(),
+            pos: self.dropped_events + self.trace_log.len() as u64,
+        };
+        if emitted {
+            self.emit_marker(&marker);
+        }
+        // 不保留trace_log时标记也没有可以对应的位置
+        if self.log_limit != LogLimit::Disabled {
+            self.markers.push_back(marker);
+        }
+        Ok(())
+    }
+
+    pub fn set_magic(&mut self, magic: MagicConfig) {
+        self.magic = magic;
+    }
+
+    pub fn magic(&self) -> &MagicConfig {
+        &self.magic
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    #[cfg(test)]
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    // 执行客户机通过魔数指令请求的操作，返回true表示需要输出栈
+    pub fn run_magic(&mut self, op: MagicOp, label_addr: u64) -> bool {
+        let kind = match op {
+            MagicOp::TraceOn | MagicOp::TraceOff => {
+                self.paused = op == MagicOp::TraceOff;
+                // 暂停之后可能很久都没有新的事件，先把已经输出的事件写入文件
+                if self.paused {
+                    let _ = self.flush_sinks();
+                }
+                return false;
+            }
+            MagicOp::DumpStack => return true,
+            MagicOp::Mark => MarkerKind::Instant,
+            MagicOp::RegionBegin => MarkerKind::Begin,
+            MagicOp::RegionEnd => MarkerKind::End,
+        };
+        // 读不到客户机内存时用地址作为名字
+        let label = self
+            .guest_mem
+            .as_ref()
+            .and_then(|mem| mem.read_label(label_addr, self.string_cap))
+            .unwrap_or_else(|| format!("{:#x}", label_addr));
+        // 没有对应开始的结束已经打印了警告，不影响客户机继续运行
+        let _ = self.mark(kind, &label);
+        false
+    }
+
+    pub fn markers(&self) -> &VecDeque<Marker> {
+        &self.markers
+    }
+
+    pub fn open_regions(&self) -> Vec<&str> {
+        self.open_regions.iter().map(|(x, _)| x.as_str()).collect()
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events
     }
 
     pub fn get_time_from_index(&self, idx: usize) -> u64 {
@@ -241,30 +854,46 @@ impl Manager {
     }
 
     pub fn get_time_base_end(&self) -> u64 {
-        if let Some(time) = self.time_base.last() {
+        if let Some(time) = self.time_base.back() {
             time.to_owned()
         } else {
             0
         }
     }
 
+    // 找到pc所在的reader，优先main reader
+    fn find_reader(&self, pc: u64) -> Option<CurReader> {
+        if self.main_reader.reader_cmp(pc) == Ordering::Equal {
+            return Some(CurReader::MainReader);
+        }
+        self.prog_readers
+            .as_ref()
+            .and_then(|readers| {
+                readers
+                    .iter()
+                    .find(|x| self.reader_visible(x) && x.reader_cmp(pc) == Ordering::Equal)
+            })
+            .map(|reader| self.elfreader_to_curreader(reader))
+    }
+
     fn first_add_function(&mut self, pc: u64, paras: Option<&Vec<u64>>) {
-        assert!(
-            self.cur_reader == CurReader::MainReader,
-            "Is not first function"
-        );
         assert!(self.func_stack.is_empty(), "Is not first function");
-        assert!(self.trace_log.is_empty(), "Is not first function");
+        assert!(self.cur_func.is_none(), "Is not first function");
+        // 新的上下文不一定从main reader开始
+        let reader_enum = self.find_reader(pc).unwrap_or(CurReader::MainReader);
+        self.cur_reader = reader_enum;
         let func_info = match self.cur_reader().find(pc) {
             Some(x) => {
-                debug_println!("Init function add {} in Main Reader", x.name);
+                debug_println!("Init function add {} in {}", x.name, self.cur_reader().name);
                 FuncInstance::new(
                     x.id,
                     FunType::LocalFunc,
-                    CurReader::MainReader,
+                    reader_enum,
                     self.get_time(),
+                    self.cur_call_site(),
                     paras,
                 )
+                .with_nonlocal_exit(self.is_nonlocal_exit(reader_enum, x.id))
             }
             None => {
                 debug_println!("Init function add anonymous function");
@@ -272,15 +901,16 @@ impl Manager {
                 FuncInstance::new(
                     0,
                     FunType::ExternalFunc,
-                    CurReader::MainReader,
+                    reader_enum,
                     self.get_time(),
+                    self.cur_call_site(),
                     paras,
                 )
             }
         };
-        let func_info = Rc::new(func_info);
+        let func_info = self.new_frame(func_info);
         self.trace_log_push(func_info.clone());
-        self.func_stack.push(func_info);
+        self.push_frame(func_info);
     }
 
     fn check_bound(&self, func_ins: &FuncInstance, pc: u64) -> bool {
@@ -354,17 +984,25 @@ impl Manager {
                 named_func.func_type,
                 cur_reader,
                 self.get_time(),
+                self.cur_call_site(),
                 paras,
-            );
-            let func_ins = Rc::new(func_ins);
+            )
+            .with_nonlocal_exit(self.nonlocal_exits.contains(&named_func.name));
+            let func_ins = self.new_frame(func_ins);
             self.trace_log_push(func_ins.clone());
-            self.func_stack.push(func_ins);
+            self.push_frame(func_ins);
         } else {
             // 如果没有找到，那就是匿名函数
-            let func_ins =
-                FuncInstance::new(0, FunType::ExternalFunc, cur_reader, self.get_time(), paras);
-            let func_ins = Rc::new(func_ins);
-            if let Some(x) = self.trace_log.last() {
+            let func_ins = FuncInstance::new(
+                0,
+                FunType::ExternalFunc,
+                cur_reader,
+                self.get_time(),
+                self.cur_call_site(),
+                paras,
+            );
+            let func_ins = self.new_frame(func_ins);
+            if let Some(x) = self.cur_func.as_ref() {
                 if x.func_type == FunType::LocalFunc {
                     self.trace_log_push(func_ins.clone());
                 }
@@ -373,7 +1011,7 @@ impl Manager {
             // 如果是就不继续添加，不是就继续添加
             if let Some(x) = self.func_stack.last() {
                 if x.func_type == FunType::LocalFunc {
-                    self.func_stack.push(func_ins);
+                    self.push_frame(func_ins);
                 }
             }
         }
@@ -382,9 +1020,9 @@ impl Manager {
     fn noram_add_function(&mut self, pc: u64, paras: Option<&Vec<u64>>) {
         // 这个函数假设了已经需要切换函数（也就是check_bound失败）
         // 这个函数需要切换cur reader
-        assert!(!self.trace_log.is_empty());
+        assert!(self.cur_func.is_some());
         let cur_reader = self.cur_reader();
-        if cur_reader.reader_cmp(pc) == Ordering::Equal {
+        if self.reader_visible(cur_reader) && cur_reader.reader_cmp(pc) == Ordering::Equal {
             let reader_enum = self.elfreader_to_curreader(cur_reader);
             self.build_ins_and_push(reader_enum, pc, paras);
         } else if self.main_reader.reader_cmp(pc) == Ordering::Equal {
@@ -393,22 +1031,25 @@ impl Manager {
             self.build_ins_and_push(reader_enum, pc, paras);
         } else if self.prog_readers.is_none() {
             // 这里主要应对没有传入完整的elf的情况，保证可用性的判断
-            let func_ins = FuncInstance::new_with_nullreader(0, self.get_time(), paras);
-            let func_ins = Rc::new(func_ins);
-            if let Some(x) = self.trace_log.last() {
+            let func_ins =
+                FuncInstance::new_with_nullreader(0, self.get_time(), self.cur_call_site(), paras);
+            let func_ins = self.new_frame(func_ins);
+            if let Some(x) = self.cur_func.as_ref() {
                 if x.func_type == FunType::LocalFunc {
                     self.trace_log_push(func_ins.clone());
                 }
             }
             if let Some(x) = self.func_stack.last() {
                 if x.func_type == FunType::LocalFunc {
-                    self.func_stack.push(func_ins);
+                    self.push_frame(func_ins);
                 }
             }
         } else {
             // 这里需要额外考虑没有传入progs reader但是有外部函数的情况
             let readers = self.prog_readers.as_ref().expect("Unexpected behaviour!");
-            let reader_opt = readers.iter().find(|x| x.reader_cmp(pc) == Ordering::Equal);
+            let reader_opt = readers
+                .iter()
+                .find(|x| self.reader_visible(x) && x.reader_cmp(pc) == Ordering::Equal);
             if let Some(reader) = reader_opt {
                 let reader_enum = self.elfreader_to_curreader(reader);
                 self.cur_reader = reader_enum;
@@ -421,16 +1062,21 @@ impl Manager {
                 add as an anonymous function instance",
                     pc
                 );
-                let func_ins = FuncInstance::new_with_nullreader(0, self.get_time(), paras);
-                let func_ins = Rc::new(func_ins);
-                if let Some(x) = self.trace_log.last() {
+                let func_ins = FuncInstance::new_with_nullreader(
+                    0,
+                    self.get_time(),
+                    self.cur_call_site(),
+                    paras,
+                );
+                let func_ins = self.new_frame(func_ins);
+                if let Some(x) = self.cur_func.as_ref() {
                     if x.func_type == FunType::LocalFunc {
                         self.trace_log_push(func_ins.clone());
                     }
                 }
                 if let Some(x) = self.func_stack.last() {
                     if x.func_type == FunType::LocalFunc {
-                        self.func_stack.push(func_ins);
+                        self.push_frame(func_ins);
                     }
                 }
             }
@@ -439,21 +1085,43 @@ impl Manager {
 
     // 这里的external和elf_reader的func vec的external意义不完全相同
     // 如果找不到就会标记external，所以manager的external算是func vec的external的超集
-    pub fn jmp_check_add_function(&mut self, pc: u64, paras: Option<&Vec<u64>>) {
-        if self.trace_log.is_empty() {
+    // ret_addr是调用指令写入链接寄存器的返回地址，普通的跳转传入None
+    pub fn jmp_check_add_function(
+        &mut self,
+        pc: u64,
+        ret_addr: Option<u64>,
+        paras: Option<&Vec<u64>>,
+    ) {
+        self.sp_unwound = false;
+        self.cur_ret_addr = ret_addr;
+        if self.cur_func.is_none() {
             assert!(self.func_stack.is_empty());
             self.first_add_function(pc, paras);
         } else {
             let last_func = self
-                .trace_log
-                .last()
+                .cur_func
+                .as_ref()
                 .expect("Last func is null, unexpected behaviour");
-            if !self.check_bound(last_func, pc) {
+            // 带返回地址的跳转一定是调用，即使还在函数内部（递归）
+            if ret_addr.is_some() || !self.check_bound(last_func, pc) {
                 self.noram_add_function(pc, paras);
             }
         }
     }
 
+    // 找到与当前sp和返回地址完全一致的栈帧，返回它调用者的位置
+    fn match_call_site(&self, pc: u64) -> Option<usize> {
+        self.func_stack
+            .iter()
+            .enumerate()
+            .skip(1)
+            .rev()
+            .find(|(_, item)| {
+                item.call_site.ret_addr == Some(pc) && item.call_site.sp == self.cur_sp
+            })
+            .map(|(idx, _)| idx - 1)
+    }
+
     fn print_stack_log(&self) {
         if self.func_stack().len() >= 500 {
             return;
@@ -495,89 +1163,752 @@ impl Manager {
         }
     }
 
-    // 这里的pc需要传入返回后的第一条指令的pc，返回值则是在ret的时候收集的
-    pub fn ret_pop_function(&mut self, pc: u64, ret_val: Option<(u64, Option<u64>)>) {
-        // Cell救我狗命
+    // 弹出栈顶的函数，为它设置end_time并累计到profile中
+    fn pop_frame(&mut self, unwound: bool) -> Option<Rc<FuncInstance>> {
+        let element = self.func_stack.pop()?;
+        let end_time = self.get_time();
+        element.set_end_time(end_time);
+        let ret_val = if unwound {
+            None
+        } else {
+            self.cur_ret_val.take()
+        };
+        if ret_val.is_some() {
+            element.set_ret_val(ret_val, self.show_context);
+        }
+        self.run_hooks(&element, false, ret_val);
+        if self.show_context && !element.hidden {
+            *element.args_text.borrow_mut() = self.format_args(&element);
+        }
+        // 将弹出函数的参数设置为None，避免内存占用过大
+        element.set_paras(None);
+        element.strings.borrow_mut().clear();
+        element.unwound.set(unwound);
+
+        let inclusive = end_time.saturating_sub(element._start_time);
+        let exclusive = inclusive.saturating_sub(element.child_time.get());
+        if let Some(parent) = self.func_stack.last() {
+            // 被过滤的函数自身的时间留给父函数，只向上传递可见的子函数的时间
+            let charged = if element.hidden {
+                element.child_time.get()
+            } else {
+                inclusive
+            };
+            parent.child_time.set(parent.child_time.get() + charged);
+        }
+        // 恢复的栈帧不知道真正的开始时间，调用事件也没有输出过，所以不计入统计
+        if element.hidden || element.seeded {
+            return Some(element);
+        }
+        self.profiler.record(element.key(), inclusive, exclusive);
+        if let Some(parent) = element.visible_parent() {
+            self.call_graph
+                .add_time(parent.key(), element.key(), inclusive);
+        }
+        let kind = if unwound {
+            EventKind::Unwind
+        } else {
+            EventKind::Return
+        };
+        self.emit(kind, &element);
+        Some(element)
+    }
 
-        let cur_func = self
-            .trace_log
-            .last()
-            .expect("Ret must have current Function");
-        cur_func.set_end_and_ret(self.get_time(), ret_val, self.show_context);
+    fn new_frame(&self, func_ins: FuncInstance) -> Rc<FuncInstance> {
+        let hidden = !self.frame_visible(&func_ins);
+        self.trim_args(&func_ins);
+        self.capture_strings(&func_ins);
+        Rc::new(
+            func_ins
+                .with_parent(self.func_stack.last())
+                .with_ctx(self.ctx)
+                .with_hidden(hidden)
+                .with_seeded(self.seeding),
+        )
+    }
 
-        let mut has_ext = false;
-        let res = self.func_stack.iter().enumerate().rev().find(|(_, item)| {
-            if item.func_type == FunType::ExternalFunc {
-                has_ext |= true;
-                return false;
-            }
-            self.check_bound(item, pc)
-        });
-        if let Some((idx, target)) = res {
-            if idx == self.func_stack.len() - 1 {
-                self.print_stack_log();
-                // 很奇怪，明明做了校验，为什么还能跑？
-                panic!("Ret target is on the top of ret stack, Unexpected behaviour");
-            }
-            let t_id = target.id;
-            let t_reader = target.reader;
-            let target = target.clone();
-            while let Some(element) = self.func_stack.pop() {
-                // 这里已经pop了，所以拿到的len就是对应刚才弹出的顶层元素的idx
-                // 不能让返回到的那个函数pop，所以需要idx对应的前一个元素pop后就结束while
-                if self.func_stack.len() == idx + 1 {
-                    break;
-                } else {
-                    // 为弹出的函数设置end_time
-                    element.set_end_time(self.get_time());
-                    // 将弹出函数的参数设置为None，避免内存占用过大
-                    element.set_paras(None);
-                }
-            }
-            let elem = self
-                .func_stack
-                .last()
-                .expect("Current stack should not empty");
-            assert!(elem.id == t_id);
-            assert!(elem.reader == t_reader);
-            self.trace_log_push(target);
-        } else if !has_ext {
-            // 因为如果栈内没有外部函数，就不可能返回到区域外
-            // 要么就是我写错了，要么就是有一些我不了解的机制
-            // 这时候就直接panic了
-            debug_println!("Failed PC: {}", pc);
-            self.print_stack_log();
-            panic!("Unexpected behaviour, abort!");
-        } else if self
-            .trace_log
-            .last()
-            .expect("In ret, log can not be empty")
-            .func_type
-            != FunType::ExternalFunc
-        {
-            // 此时需要记录一个External function
-            // 为了简单起见，就不check reader了
-            let func_ins = FuncInstance::new_with_nullreader(0, self.get_time(), None);
-            let func_ins = Rc::new(func_ins);
-            self.trace_log_push(func_ins)
-        } // return
+    // 新的栈帧压栈之后是否可见
+    fn frame_visible(&self, func_ins: &FuncInstance) -> bool {
+        if self.filter.is_empty() {
+            return true;
+        }
+        let key = func_ins.key();
+        self.filter.is_visible(
+            &self.key_reader_name(&key),
+            &self.key_name(&key),
+            self.func_stack.len() + 1,
+        )
     }
 
-    pub fn func_stack(&self) -> &Vec<Rc<FuncInstance>> {
-        &self.func_stack
+    // 只保留函数真正用到的参数寄存器，没有输出和回调需要时都不保留
+    fn trim_args(&self, func_ins: &FuncInstance) {
+        if !self.show_context && self.hooks.is_empty() {
+            func_ins.set_paras(None);
+            return;
+        }
+        let count = self
+            .func_sig(func_ins)
+            .map_or(MAX_REG_ARGS, |sig| sig.reg_count());
+        if let Some(args) = func_ins.paras.borrow_mut().as_mut() {
+            args.truncate(count);
+        }
     }
 
-    pub fn trace_log(&self) -> &Vec<Rc<FuncInstance>> {
-        &self.trace_log
+    // 参数寄存器的值在函数执行过程中会被覆盖，所以字符串要在调用时读取
+    fn capture_strings(&self, func_ins: &FuncInstance) {
+        let Some(mem) = self.guest_mem.as_ref().filter(|_| self.show_context) else {
+            return;
+        };
+        let Some(sig) = self.func_sig(func_ins) else {
+            return;
+        };
+        let paras = func_ins.paras();
+        let Some(args) = paras.as_ref() else {
+            return;
+        };
+        let strings = sig
+            .string_regs()
+            .into_iter()
+            .filter_map(|reg| {
+                let addr = *args.get(reg).filter(|x| **x != 0)?;
+                Some((reg, mem.read_c_string(addr, self.string_cap)?))
+            })
+            .collect();
+        *func_ins.strings.borrow_mut() = strings;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::vec;
+    pub fn set_guest_mem(&mut self, guest_mem: Option<GuestMem>) {
+        self.guest_mem = guest_mem;
+    }
 
-    use super::*;
-    use std::fs::File;
+    pub fn set_string_cap(&mut self, string_cap: usize) {
+        self.string_cap = string_cap;
+    }
+
+    pub fn add_signature(&mut self, name: String, sig: FuncSig) {
+        self.signatures.insert(name, sig);
+    }
+
+    // 优先使用DWARF中的签名，其次是用户提供的签名表
+    // 用户指定的字符串参数总是生效，合并到DWARF的签名中
+    pub fn func_sig(&self, func_ins: &FuncInstance) -> Option<Cow<'_, FuncSig>> {
+        let func = self.get_func_from_ins(func_ins)?;
+        let user = self.signatures.get(&func.name);
+        match (func.sig.as_ref(), user) {
+            (Some(sig), Some(user)) if user.has_strings() => {
+                let mut sig = sig.clone();
+                sig.merge_strings(user);
+                Some(Cow::Owned(sig))
+            }
+            (Some(sig), _) => Some(Cow::Borrowed(sig)),
+            (None, user) => user.map(Cow::Borrowed),
+        }
+    }
+
+    // 形如(a0=-1, a1=true)的参数列表，没有记录参数时为None
+    pub fn format_args(&self, func_ins: &FuncInstance) -> Option<String> {
+        let paras = func_ins.paras();
+        let Some(args) = paras.as_ref() else {
+            return func_ins.args_text.borrow().clone();
+        };
+        if let Some(sig) = self.func_sig(func_ins) {
+            return Some(sig.format_args(args, &func_ins.strings.borrow()));
+        }
+        // 没有签名时按照原样输出记录的所有寄存器
+        let list = args
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| format!("a{}={:#x}", idx, value))
+            .collect::<Vec<_>>();
+        Some(format!("({})", list.join(", ")))
+    }
+
+    // 按照返回值类型格式化，void函数和没有记录返回值时为None
+    pub fn format_ret(&self, func_ins: &FuncInstance) -> Option<String> {
+        let ret_val = func_ins.ret_val()?;
+        match self.func_sig(func_ins) {
+            Some(sig) => sig.format_ret(ret_val),
+            None => Some(format!("{:#x}", ret_val.0)),
+        }
+    }
+
+    pub fn set_filter(&mut self, filter: FuncFilter) {
+        self.filter = filter;
+    }
+
+    fn push_frame(&mut self, func_ins: Rc<FuncInstance>) {
+        self.func_stack.push(func_ins.clone());
+        // 恢复的函数并不是现在才进入的，不触发回调，也不输出事件
+        if func_ins.seeded {
+            return;
+        }
+        // 回调不受过滤的影响
+        self.run_hooks(&func_ins, true, None);
+        if func_ins.hidden {
+            return;
+        }
+        if let Some(parent) = func_ins.visible_parent() {
+            self.call_graph.add_call(parent.key(), func_ins.key());
+        }
+        self.emit(EventKind::Call, &func_ins);
+    }
+
+    pub fn add_hook(&mut self, hook: FuncHook) {
+        self.hooks.push(hook);
+    }
+
+    // 取出已经触发的回调，由调用者在释放锁之后执行
+    pub fn take_pending_hooks(&mut self) -> Vec<PendingHook> {
+        std::mem::take(&mut self.pending_hooks)
+    }
+
+    fn run_hooks(
+        &mut self,
+        func_ins: &FuncInstance,
+        enter: bool,
+        ret_val: Option<(u64, Option<u64>)>,
+    ) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let key = func_ins.key();
+        let name = self.key_name(&key);
+        let addr = self
+            .get_func_from_ins(func_ins)
+            .map(|func| func.start)
+            .unwrap_or(0);
+        let matched = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.target.is_match(&name, addr))
+            .filter_map(|hook| {
+                if enter {
+                    hook.on_enter.clone()
+                } else {
+                    hook.on_exit.clone()
+                }
+            })
+            .collect::<Vec<HookFn>>();
+        if matched.is_empty() {
+            return;
+        }
+        // 回调可能会调用ftrace的接口，这里持有manager的锁，所以只记录下来
+        self.pending_hooks.push(PendingHook {
+            hooks: matched,
+            hart: self.hart,
+            ctx: func_ins.ctx,
+            name,
+            reader: self.key_reader_name(&key),
+            addr,
+            args: func_ins.paras().clone(),
+            ret_val,
+        });
+    }
+
+    pub fn add_sink(&mut self, sink: SharedSink) {
+        self.sinks.push(sink);
+    }
+
+    pub fn flush_sinks(&self) -> Result<(), isize> {
+        let mut result = Ok(());
+        for sink in self.sinks.iter() {
+            if let Err(err) = sink.lock().unwrap().flush() {
+                println!("Warning: can not flush trace sink: {}", err);
+                result = Err(-1);
+            }
+        }
+        result
+    }
+
+    // 暂停时不输出新的调用，但是暂停之前已经输出的调用结束时仍然输出，保证事件成对
+    fn emit(&self, kind: EventKind, func_ins: &FuncInstance) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        if kind == EventKind::Call {
+            if self.paused {
+                return;
+            }
+            func_ins.emitted.set(true);
+        } else if !func_ins.emitted.get() {
+            return;
+        }
+        let key = func_ins.key();
+        let event = TraceEvent {
+            kind,
+            hart: self.hart,
+            ctx: func_ins.ctx,
+            time: self.get_time(),
+            depth: self.func_stack.len(),
+            name: format!("{}@{}", self.key_reader_name(&key), self.key_name(&key)),
+            args: if kind == EventKind::Call && self.show_context {
+                self.format_args(func_ins)
+            } else {
+                None
+            },
+            ret: if kind == EventKind::Return {
+                self.format_ret(func_ins)
+            } else {
+                None
+            },
+        };
+        for sink in self.sinks.iter() {
+            if let Err(err) = sink.lock().unwrap().record(&event) {
+                println!("Warning: can not write trace sink: {}", err);
+            }
+        }
+    }
+
+    fn emit_marker(&self, marker: &Marker) {
+        let event = TraceEvent {
+            kind: marker.kind.event_kind(),
+            hart: self.hart,
+            ctx: marker.ctx,
+            time: marker.time,
+            depth: marker.depth,
+            name: marker.label.clone(),
+            args: None,
+            ret: None,
+        };
+        for sink in self.sinks.iter() {
+            if let Err(err) = sink.lock().unwrap().record(&event) {
+                println!("Warning: can not write trace sink: {}", err);
+            }
+        }
+    }
+
+    // 当前的调用图，还在栈上的函数按照运行到现在计算
+    pub fn call_graph(&self) -> CallGraph {
+        let mut call_graph = self.call_graph.clone();
+        let now = self.get_time();
+        for element in self
+            .stacks()
+            .into_iter()
+            .flat_map(|(_, stack)| stack.iter())
+            .filter(|x| !x.hidden && !x.seeded)
+        {
+            if let Some(parent) = element.visible_parent() {
+                let inclusive = now.saturating_sub(element._start_time);
+                call_graph.add_time(parent.key(), element.key(), inclusive);
+            }
+        }
+        call_graph
+    }
+
+    // 当前的profile，还在栈上的函数按照运行到现在计算
+    pub fn profile(&self) -> Profiler {
+        let mut profiler = self.profiler.clone();
+        let now = self.get_time();
+        for (_, stack) in self.stacks() {
+            let mut live_child = 0;
+            for element in stack.iter().rev() {
+                if element.hidden {
+                    live_child += element.child_time.get();
+                    continue;
+                }
+                let inclusive = now.saturating_sub(element._start_time);
+                let exclusive = inclusive.saturating_sub(element.child_time.get() + live_child);
+                if !element.seeded {
+                    profiler.record(element.key(), inclusive, exclusive);
+                }
+                live_child = inclusive;
+            }
+        }
+        profiler
+    }
+
+    // 输出时使用的函数名
+    pub fn key_name(&self, key: &FuncKey) -> String {
+        if key.func_type == FunType::ExternalFunc {
+            return "unknown".to_string();
+        }
+        key.reader
+            .and_then(|reader| self.get_reader(&reader).get_func(key.id))
+            .map(|func| func.name.clone())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    pub fn key_reader_name(&self, key: &FuncKey) -> String {
+        key.reader
+            .map(|reader| self.get_reader(&reader).name.clone())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    // pc所在的函数，和FuncInstance::key()一样用reader和函数id标识
+    fn pc_func(&self, pc: u64) -> Option<(CurReader, u32)> {
+        let reader = self.find_reader(pc)?;
+        let func = self.get_reader(&reader).find(pc)?;
+        Some((reader, func.id))
+    }
+
+    // reader@name的形式
+    pub fn pc_name(&self, pc: u64) -> String {
+        match self.find_reader(pc) {
+            Some(reader) => {
+                let reader = self.get_reader(&reader);
+                let name = reader.find(pc).map_or("unknown", |func| func.name.as_str());
+                format!("{}@{}", reader.name, name)
+            }
+            None => "unknown".to_string(),
+        }
+    }
+
+    // 不依赖影子栈，用CFI或者帧指针从当前的寄存器回溯，需要先设置读取内存的回调
+    pub fn backtrace(&self, pc: u64, regs: &[u64]) -> Vec<BtFrame> {
+        unwind(
+            pc,
+            regs,
+            self.guest_mem.as_ref(),
+            |pc| {
+                self.find_reader(pc)
+                    .and_then(|reader| self.get_reader(&reader).cfi().rule(pc))
+            },
+            |pc| self.find_reader(pc).is_some(),
+        )
+    }
+
+    // 在运行中途开始追踪时，回溯当前的客户机栈，把还在运行的函数从外到内压栈
+    // 之后从这些函数返回时就能和调用对应上，返回压栈的栈帧数
+    pub fn attach(&mut self, pc: u64, regs: &[u64]) -> Result<usize, isize> {
+        if self.cur_func.is_some() {
+            println!(
+                "Warning: hart {} is already tracing, reset it before attach",
+                self.hart
+            );
+            return Err(-1);
+        }
+        let frames = self.backtrace(pc, regs);
+        self.seeding = true;
+        for (idx, frame) in frames.iter().enumerate().rev() {
+            // 调用frames[idx]时的sp就是调用者那一帧的sp，返回地址就是调用者那一帧的pc
+            let caller = frames.get(idx + 1);
+            self.cur_sp = caller.map(|x| x.sp);
+            self.cur_ret_addr = caller.map(|x| x.pc);
+            // 返回地址可能正好是下一个函数的开头，用它的前一个字节查找
+            let pc = if idx == 0 { frame.pc } else { frame.pc - 1 };
+            if self.cur_func.is_none() {
+                self.first_add_function(pc, None);
+            } else {
+                self.noram_add_function(pc, None);
+            }
+        }
+        self.seeding = false;
+        self.cur_sp = regs.get(2).copied();
+        self.cur_ret_addr = None;
+        Ok(frames.len())
+    }
+
+    // 比较回溯的结果和当前上下文的影子栈，返回不一致的地方
+    // 每一项是回溯结果中的位置、回溯得到的函数和影子栈中的函数，缺少的一方为None
+    // 两边按照栈帧的CFA（调用时的sp）对齐，一边漏掉的栈帧不会让后面所有的层都错开
+    pub fn compare_backtrace(
+        &self,
+        frames: &[BtFrame],
+    ) -> Vec<(usize, Option<String>, Option<String>)> {
+        let shadows = self.func_stack.iter().rev().collect::<Vec<_>>();
+        let shadow_name = |shadow: &FuncInstance| {
+            let key = shadow.key();
+            format!("{}@{}", self.key_reader_name(&key), self.key_name(&key))
+        };
+        let mut diff = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < frames.len() || j < shadows.len() {
+            // 一帧的CFA就是调用者那一帧的sp，最外层的帧不知道
+            let frame_cfa = frames.get(i + 1).map(|x| x.sp);
+            match (frames.get(i), shadows.get(j)) {
+                (Some(frame), Some(shadow)) => {
+                    let shadow_cfa = shadow.call_site.sp;
+                    match (frame_cfa, shadow_cfa) {
+                        // 回溯多出来的帧，影子栈漏掉了这次调用
+                        (Some(a), Some(b)) if a < b => {
+                            diff.push((i, Some(self.pc_name(frame.pc)), None));
+                            i += 1;
+                        }
+                        // 影子栈多出来的帧，外部函数没有CFI，回溯看不到它们，不算不一致
+                        (Some(a), Some(b)) if a > b => {
+                            if shadow.func_type != FunType::ExternalFunc {
+                                diff.push((i, None, Some(shadow_name(shadow))));
+                            }
+                            j += 1;
+                        }
+                        // CFA相同或者有一方不知道时认为是同一帧
+                        _ => {
+                            if !self.same_frame(frame, shadow) {
+                                diff.push((
+                                    i,
+                                    Some(self.pc_name(frame.pc)),
+                                    Some(shadow_name(shadow)),
+                                ));
+                            }
+                            i += 1;
+                            j += 1;
+                        }
+                    }
+                }
+                (Some(frame), None) => {
+                    diff.push((i, Some(self.pc_name(frame.pc)), None));
+                    i += 1;
+                }
+                (None, Some(shadow)) => {
+                    if shadow.func_type != FunType::ExternalFunc {
+                        diff.push((i, None, Some(shadow_name(shadow))));
+                    }
+                    j += 1;
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+        diff
+    }
+
+    // 外部函数对应的pc不在任何已知的函数里
+    fn same_frame(&self, frame: &BtFrame, shadow: &FuncInstance) -> bool {
+        let key = shadow.key();
+        let shadow_func = key
+            .reader
+            .filter(|_| key.func_type == FunType::LocalFunc)
+            .map(|reader| (reader, key.id));
+        self.pc_func(frame.pc) == shadow_func
+    }
+
+    // 同步当前的sp，需要在每次跳转前调用
+    // 栈向下增长，所以记录的sp小于当前sp的栈帧都已经被longjmp之类的跳转越过了
+    // 这些栈帧按照unwound弹出，而不是当作正常返回
+    pub fn sync_sp(&mut self, sp: u64) {
+        self.cur_sp = Some(sp);
+        let mut unwound = false;
+        // 栈底的函数没有调用者可以回去，始终保留
+        while self.func_stack.len() > 1 {
+            let top = self
+                .func_stack
+                .last()
+                .expect("Current stack should not empty");
+            if top.call_site.sp.is_none_or(|top_sp| top_sp >= sp) {
+                break;
+            }
+            self.pop_frame(true);
+            unwound = true;
+        }
+        if unwound {
+            // 记录回到了哪一个函数
+            let top = self
+                .func_stack
+                .last()
+                .expect("Current stack should not empty")
+                .clone();
+            debug_println!("Unwind stack to sp: 0x{:X}", sp);
+            self.trace_log_push(top);
+            self.sp_unwound = true;
+        }
+    }
+
+    // 这里的pc需要传入返回后的第一条指令的pc，返回值则是在ret的时候收集的
+    pub fn ret_pop_function(&mut self, pc: u64, ret_val: Option<(u64, Option<u64>)>) {
+        // Cell救我狗命
+
+        if self.cur_func.is_none() {
+            // 这个上下文还没有见过任何调用，就把返回到的函数当作栈底
+            self.first_add_function(pc, None);
+            return;
+        }
+        let cur_func = self
+            .cur_func
+            .as_ref()
+            .expect("Ret must have current Function");
+        cur_func.set_end_and_ret(self.get_time(), ret_val, self.show_context);
+        self.cur_ret_val = ret_val;
+
+        // 栈上有longjmp之类的函数时，返回的目标不一定符合调用关系，需要重新同步
+        let resync =
+            std::mem::take(&mut self.sp_unwound) || self.func_stack.iter().any(|x| x.nonlocal_exit);
+        let mut has_ext = false;
+        // 优先用sp和返回地址精确匹配，递归的时候地址范围无法区分同一个函数的多个栈帧
+        let res = if let Some(idx) = self.match_call_site(pc) {
+            Some((idx, &self.func_stack[idx]))
+        } else {
+            self.func_stack.iter().enumerate().rev().find(|(_, item)| {
+                if item.func_type == FunType::ExternalFunc {
+                    has_ext |= true;
+                    return false;
+                }
+                self.check_bound(item, pc)
+            })
+        };
+        if let Some((idx, target)) = res {
+            if idx == self.func_stack.len() - 1 && resync {
+                // 已经按照sp回溯到了目标函数，只需要记录回到了它
+                let target = target.clone();
+                self.trace_log_push(target);
+                return;
+            } else if idx == self.func_stack.len() - 1 {
+                // 很奇怪，明明做了校验，为什么还能跑？
+                let target = target.clone();
+                self.report_desync(
+                    pc,
+                    "Ret target is on the top of ret stack, Unexpected behaviour",
+                );
+                // 返回的目标已经在栈顶，不需要弹出任何栈帧
+                self.trace_log_push(target);
+                return;
+            }
+            let t_id = target.id;
+            let t_reader = target.reader;
+            let target = target.clone();
+            // 不能让返回到的那个函数pop，所以只弹出到idx为止
+            while self.func_stack.len() > idx + 1 {
+                self.pop_frame(false);
+            }
+            let elem = self
+                .func_stack
+                .last()
+                .expect("Current stack should not empty");
+            assert!(elem.id == t_id);
+            assert!(elem.reader == t_reader);
+            self.trace_log_push(target);
+        } else if !has_ext && !resync {
+            // 因为如果栈内没有外部函数，就不可能返回到区域外
+            // 要么就是我写错了，要么就是有一些我不了解的机制
+            // 严格模式下直接panic，否则从返回的目标重建栈
+            debug_println!("Failed PC: {}", pc);
+            self.report_desync(pc, "Unexpected behaviour, abort!");
+            self.rebuild_stack(pc);
+        } else if self
+            .cur_func
+            .as_ref()
+            .expect("In ret, log can not be empty")
+            .func_type
+            != FunType::ExternalFunc
+        {
+            // 此时需要记录一个External function
+            // 为了简单起见，就不check reader了
+            let func_ins =
+                FuncInstance::new_with_nullreader(0, self.get_time(), self.cur_call_site(), None);
+            let func_ins = self.new_frame(func_ins);
+            self.trace_log_push(func_ins)
+        } // return
+    }
+
+    pub fn set_desync_policy(&mut self, desync_policy: DesyncPolicy) {
+        self.desync_policy = desync_policy;
+    }
+
+    pub fn desync_count(&self) -> u64 {
+        self.desync_count
+    }
+
+    // 记录一次栈失去同步的现场，严格模式下输出整个日志之后panic
+    fn report_desync(&mut self, pc: u64, reason: &str) {
+        if self.desync_policy == DesyncPolicy::Strict {
+            self.print_stack_log();
+            panic!("{}", reason);
+        }
+        self.desync_count += 1;
+        let target = self
+            .find_reader(pc)
+            .and_then(|reader| self.get_reader(&reader).find(pc))
+            .map(|func| func.name.clone())
+            .unwrap_or_else(|| "unknown".to_string());
+        let stack = self
+            .func_stack
+            .iter()
+            .map(|x| self.key_name(&x.key()))
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        println!(
+            "Warning: ftrace desync #{} on hart {} context {}: {}, pc: 0x{:x}, target: {}, stack: [{}]",
+            self.desync_count, self.hart, self.ctx, reason, pc, target, stack
+        );
+    }
+
+    // 丢弃当前上下文的整个栈，把返回的目标当作新的栈底
+    fn rebuild_stack(&mut self, pc: u64) {
+        while self.pop_frame(true).is_some() {}
+        self.cur_func = None;
+        self.first_add_function(pc, None);
+    }
+
+    pub fn func_stack(&self) -> &Vec<Rc<FuncInstance>> {
+        &self.func_stack
+    }
+
+    // 切换到另一个上下文（进程）的影子栈，编号可以是satp/ASID，也可以由调用者指定
+    pub fn switch_context(&mut self, ctx: u64) {
+        if ctx == self.ctx {
+            return;
+        }
+        debug_println!("Switch context from {} to {}", self.ctx, ctx);
+        let parked = ContextState {
+            func_stack: std::mem::take(&mut self.func_stack),
+            cur_func: self.cur_func.take(),
+            cur_reader: self.cur_reader,
+            cur_sp: self.cur_sp,
+        };
+        self.contexts.insert(self.ctx, parked);
+        let state = self.contexts.remove(&ctx).unwrap_or_default();
+        self.func_stack = state.func_stack;
+        self.cur_func = state.cur_func;
+        self.cur_reader = state.cur_reader;
+        self.cur_sp = state.cur_sp;
+        self.sp_unwound = false;
+        self.ctx = ctx;
+    }
+
+    pub fn ctx(&self) -> u64 {
+        self.ctx
+    }
+
+    // 所有上下文的影子栈，按照上下文编号排序
+    pub fn stacks(&self) -> Vec<(u64, &Vec<Rc<FuncInstance>>)> {
+        let mut stacks = self
+            .contexts
+            .iter()
+            .map(|(ctx, state)| (*ctx, &state.func_stack))
+            .collect::<Vec<_>>();
+        stacks.push((self.ctx, &self.func_stack));
+        stacks.sort_by_key(|(ctx, _)| *ctx);
+        stacks
+    }
+
+    pub fn trace_log(&self) -> &VecDeque<Rc<FuncInstance>> {
+        &self.trace_log
+    }
+
+    // trace_log中的每个调用和它的结束时间，按照开始时间排序，
+    // 还在栈上的函数按照运行到现在计算
+    pub fn invocations(&self) -> Vec<(Rc<FuncInstance>, u64)> {
+        let now = self.get_time();
+        let live = self
+            .stacks()
+            .into_iter()
+            .flat_map(|(_, stack)| stack.iter())
+            .map(Rc::as_ptr)
+            .collect::<HashSet<_>>();
+        let mut seen = HashSet::new();
+        let mut res = self
+            .trace_log
+            .iter()
+            .filter(|x| seen.insert(Rc::as_ptr(x)))
+            .map(|x| {
+                let end = if live.contains(&Rc::as_ptr(x)) {
+                    now
+                } else {
+                    x._end_time()
+                };
+                (x.clone(), end)
+            })
+            .collect::<Vec<_>>();
+        // 稳定排序，同时开始的父函数依然在子函数之前
+        res.sort_by_key(|(x, _)| x._start_time);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+
+    use super::*;
+    use std::fs::File;
     use std::io::Write;
     use std::thread;
     use std::time::Duration;
@@ -590,10 +1921,48 @@ mod tests {
     const BLUE_END: &str = "\x1b[0m";
 
     fn create_new(id: u32, path: &str) -> ElfReader {
-        ElfReader::new(id, path)
+        ElfReader::new(id, path).expect("Test elf should be loaded")
+    }
+
+    // 每个函数占0x100字节，从base开始依次排列
+    fn dummy_reader(name: &str, base: u64, names: &[&str]) -> ElfReader {
+        let func_vec = names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| Func {
+                id: idx as u32,
+                func_type: FunType::LocalFunc,
+                name: name.to_string(),
+                start: base + idx as u64 * 0x100,
+                end: base + 0x100 + idx as u64 * 0x100,
+                sig: None,
+            })
+            .collect::<Vec<_>>();
+        let end = base + names.len() as u64 * 0x100;
+        ElfReader::dummy(0, name, base, end, Some(func_vec))
+    }
+
+    fn dummy_manager(names: &[&str]) -> Manager {
+        Manager::from_readers(false, dummy_reader("dummy", 0x1000, names), None)
+    }
+
+    fn call(manager: &mut Manager, sp: u64, pc: u64) {
+        manager.sync_sp(sp);
+        manager.jmp_check_add_function(pc, None, None);
+    }
+
+    fn ret(manager: &mut Manager, sp: u64, pc: u64) {
+        manager.sync_sp(sp);
+        manager.ret_pop_function(pc, None);
+    }
+
+    fn top_name(manager: &Manager) -> String {
+        let top = manager.func_stack().last().unwrap();
+        manager.get_func_from_ins(top).unwrap().name.clone()
     }
 
     #[test]
+    #[allow(clippy::unnecessary_sort_by)]
     fn test_check_overlap() {
         let reader = create_new(0, "./test_elf/riscv64-nemu-interpreter");
         let reader1 = create_new(1, "./test_elf/nanos-lite-riscv64-nemu.elf");
@@ -631,13 +2000,931 @@ mod tests {
         println!("False!");
     }
 
+    #[test]
+    fn test_sync_sp_unwind() {
+        let mut manager = dummy_manager(&["_start", "main", "foo", "bar"]);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+        call(&mut manager, 0x7d00, 0x1300);
+        let bar = manager.func_stack().last().unwrap().clone();
+        assert!(manager.func_stack().len() == 4);
+
+        // 栈内的sp没有越过任何栈帧，不应该弹出
+        manager.sync_sp(0x7c00);
+        assert!(manager.func_stack().len() == 4);
+
+        // sp回到了main的位置，foo和bar都应该被unwind
+        manager.sync_sp(0x7f00);
+        assert!(manager.func_stack().len() == 2);
+        assert!(top_name(&manager) == "main");
+        assert!(bar.unwound());
+        assert!(manager.trace_log().back().unwrap().id == 1);
+
+        // 栈底的函数始终保留
+        manager.sync_sp(0xffff_0000);
+        assert!(manager.func_stack().len() == 1);
+        assert!(top_name(&manager) == "_start");
+    }
+
+    #[test]
+    fn test_nonlocal_exit_resync() {
+        let mut manager = dummy_manager(&["_start", "main", "foo", "bar", "longjmp"]);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+        call(&mut manager, 0x7d00, 0x1300);
+        call(&mut manager, 0x7c00, 0x1400);
+        assert!(manager.func_stack().last().unwrap().nonlocal_exit);
+
+        // longjmp恢复了main里setjmp时的sp，然后ret回到main
+        ret(&mut manager, 0x7e00, 0x1150);
+        assert!(top_name(&manager) == "main");
+        assert!(manager.func_stack().len() == 2);
+
+        // 没有sp的情况下，也不应该因为longjmp而panic
+        let mut manager = dummy_manager(&["_start", "main", "foo", "longjmp"]);
+        manager.jmp_check_add_function(0x1000, None, None);
+        manager.jmp_check_add_function(0x1100, None, None);
+        manager.jmp_check_add_function(0x1200, None, None);
+        manager.jmp_check_add_function(0x1300, None, None);
+        manager.ret_pop_function(0x1150, None);
+        assert!(top_name(&manager) == "main");
+        manager.ret_pop_function(0x1050, None);
+        assert!(top_name(&manager) == "_start");
+    }
+
+    #[test]
+    fn test_recursive_ret_match() {
+        let mut manager = dummy_manager(&["_start", "main", "fact"]);
+        call(&mut manager, 0x8000, 0x1000);
+        manager.sync_sp(0x7f00);
+        manager.jmp_check_add_function(0x1100, Some(0x1010), None);
+        manager.sync_sp(0x7e00);
+        manager.jmp_check_add_function(0x1200, Some(0x1120), None);
+        let fact1 = manager.func_stack().last().unwrap().clone();
+        // 递归调用虽然还在fact内部，但也应该压栈
+        manager.sync_sp(0x7d00);
+        manager.jmp_check_add_function(0x1200, Some(0x1220), None);
+        let fact2 = manager.func_stack().last().unwrap().clone();
+        manager.sync_sp(0x7c00);
+        manager.jmp_check_add_function(0x1200, Some(0x1220), None);
+        assert!(manager.func_stack().len() == 5);
+
+        // 每次ret都应该只弹出最内层的fact
+        ret(&mut manager, 0x7c00, 0x1220);
+        assert!(manager.func_stack().len() == 4);
+        assert!(Rc::ptr_eq(manager.func_stack().last().unwrap(), &fact2));
+        ret(&mut manager, 0x7d00, 0x1220);
+        assert!(manager.func_stack().len() == 3);
+        assert!(Rc::ptr_eq(manager.func_stack().last().unwrap(), &fact1));
+        ret(&mut manager, 0x7e00, 0x1120);
+        assert!(manager.func_stack().len() == 2);
+        assert!(top_name(&manager) == "main");
+    }
+
+    #[test]
+    fn test_instret_time_base() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        manager.set_clock_type(ClockType::Instret);
+        assert!(manager.clock().clock_type() == ClockType::Instret);
+        for _ in 0..5 {
+            manager.clock_tick();
+        }
+        call(&mut manager, 0x8000, 0x1000);
+        for _ in 0..3 {
+            manager.clock_tick();
+        }
+        call(&mut manager, 0x7f00, 0x1100);
+        let main = manager.func_stack().last().unwrap().clone();
+        for _ in 0..7 {
+            manager.clock_tick();
+        }
+        ret(&mut manager, 0x8000, 0x1010);
+        assert!(manager.get_time_from_index(0) == 5);
+        assert!(manager.get_time_from_index(1) == 8);
+        assert!(main._start_time() == 8);
+        assert!(main._end_time() == 15);
+        assert!(manager.get_time_base_end() == 15);
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        manager.set_clock_type(ClockType::Instret);
+        let tick = |manager: &mut Manager, n: usize| {
+            for _ in 0..n {
+                manager.clock_tick();
+            }
+        };
+        call(&mut manager, 0x8000, 0x1000);
+        tick(&mut manager, 2);
+        call(&mut manager, 0x7f00, 0x1100);
+        tick(&mut manager, 3);
+        call(&mut manager, 0x7e00, 0x1200);
+        tick(&mut manager, 4);
+        ret(&mut manager, 0x7e00, 0x1150);
+        tick(&mut manager, 1);
+        call(&mut manager, 0x7e00, 0x1200);
+        tick(&mut manager, 2);
+        ret(&mut manager, 0x7e00, 0x1150);
+        tick(&mut manager, 3);
+        ret(&mut manager, 0x7f00, 0x1050);
+
+        let profile = manager.profile();
+        let key = |id| FuncKey {
+            reader: Some(CurReader::MainReader),
+            func_type: FunType::LocalFunc,
+            id,
+        };
+        let foo = profile.get(&key(2)).unwrap();
+        assert!(foo.calls == 2);
+        assert!(foo.inclusive == 6 && foo.exclusive == 6);
+        assert!(foo.min == 2 && foo.max == 4);
+        let main = profile.get(&key(1)).unwrap();
+        assert!(main.calls == 1);
+        assert!(main.inclusive == 13 && main.exclusive == 7);
+        // _start还在栈上，按照运行到现在计算
+        let start = profile.get(&key(0)).unwrap();
+        assert!(start.inclusive == 15 && start.exclusive == 2);
+        assert!(profile.total_exclusive() == 15);
+        assert!(manager.key_name(&key(2)) == "foo");
+        assert!(manager.key_reader_name(&key(2)) == "dummy");
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let mut manager = dummy_manager(&["_start", "main", "foo", "bar"]);
+        manager.set_clock_type(ClockType::Instret);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        for _ in 0..2 {
+            call(&mut manager, 0x7e00, 0x1200);
+            call(&mut manager, 0x7d00, 0x1300);
+            manager.clock_tick();
+            ret(&mut manager, 0x7d00, 0x1250);
+            ret(&mut manager, 0x7e00, 0x1150);
+        }
+        // 栈顶是main，它的调用者是_start
+        let parent = manager
+            .func_stack()
+            .last()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .clone();
+        assert!(manager.get_func_from_ins(&parent).unwrap().name == "_start");
+        manager.clock_tick();
+
+        let key = |id| FuncKey {
+            reader: Some(CurReader::MainReader),
+            func_type: FunType::LocalFunc,
+            id,
+        };
+        let call_graph = manager.call_graph();
+        let edge = call_graph.edge(&key(1), &key(2)).unwrap();
+        assert!(edge.calls == 2 && edge.inclusive == 2);
+        let edge = call_graph.edge(&key(2), &key(3)).unwrap();
+        assert!(edge.calls == 2 && edge.inclusive == 2);
+        // main还在运行，时间按照运行到现在计算
+        let edge = call_graph.edge(&key(0), &key(1)).unwrap();
+        assert!(edge.calls == 1 && edge.inclusive == 3);
+        assert!(call_graph.callers_of(&key(3))[0].0 == key(2));
+    }
+
+    #[test]
+    fn test_switch_context() {
+        let mut manager = dummy_manager(&["_start", "main", "foo", "bar"]);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+
+        // 另一个进程从bar开始，有自己独立的栈
+        manager.switch_context(1);
+        assert!(manager.func_stack().is_empty());
+        call(&mut manager, 0x4000, 0x1300);
+        call(&mut manager, 0x3f00, 0x1200);
+        assert!(manager.func_stack().len() == 2);
+        assert!(manager.func_stack().iter().all(|x| x.ctx() == 1));
+
+        // 切换回来之后foo返回到main，不能影响另一个进程
+        manager.switch_context(0);
+        assert!(manager.func_stack().len() == 3);
+        ret(&mut manager, 0x7e00, 0x1150);
+        assert!(top_name(&manager) == "main");
+        assert!(manager.trace_log().back().unwrap().ctx() == 0);
+
+        manager.switch_context(1);
+        ret(&mut manager, 0x3f00, 0x1350);
+        assert!(top_name(&manager) == "bar");
+        assert!(manager.stacks().len() == 2);
+        assert!(manager.stacks()[0].1.len() == 2);
+
+        // 没有见过调用的上下文，ret的目标作为栈底
+        manager.switch_context(2);
+        manager.ret_pop_function(0x1150, None);
+        assert!(top_name(&manager) == "main");
+        assert!(manager.func_stack().len() == 1);
+    }
+
+    #[test]
+    fn test_asid_readers() {
+        let mut manager = dummy_manager(&["_start", "main", "schedule"]);
+        let pal = dummy_reader("pal", 0x4000_0000, &["_start", "pal_main"]).with_asid(Some(1));
+        let bird = dummy_reader("bird", 0x4000_0000, &["_start", "bird_main"]).with_asid(Some(2));
+        assert!(!Manager::check_reader_overlap(
+            &manager.main_reader,
+            Some(vec![&pal, &bird])
+        ));
+        let pal_dup = pal.clone();
+        assert!(Manager::check_reader_overlap(
+            &manager.main_reader,
+            Some(vec![&pal, &pal_dup])
+        ));
+        assert!(manager.add_prog_reader(pal) == 1);
+        assert!(manager.add_prog_reader(bird) == 2);
+
+        call(&mut manager, 0x8000, 0x1000);
+        manager.switch_context(1);
+        call(&mut manager, 0x8000, 0x4000_0100);
+        assert!(top_name(&manager) == "pal_main");
+        assert!(manager.cur_reader().name == "pal");
+
+        manager.switch_context(2);
+        call(&mut manager, 0x8000, 0x4000_0100);
+        assert!(top_name(&manager) == "bird_main");
+        // 内核在所有地址空间都可见
+        call(&mut manager, 0x7f00, 0x1200);
+        assert!(top_name(&manager) == "schedule");
+
+        // 没有对应reader的地址空间只能当作匿名函数
+        manager.switch_context(3);
+        call(&mut manager, 0x8000, 0x1100);
+        call(&mut manager, 0x7f00, 0x4000_0100);
+        let top = manager.func_stack().last().unwrap();
+        assert!(top.reader().is_none());
+        assert!(top.func_type() == FunType::ExternalFunc);
+    }
+
+    #[test]
+    fn test_log_limit() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        manager.set_log_limit(LogLimit::Events(4));
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        // 递归调用，每次都有返回地址
+        for idx in 0..10 {
+            manager.sync_sp(0x7e00 - idx * 0x10);
+            manager.jmp_check_add_function(0x1200, Some(0x1110), None);
+        }
+        assert!(manager.trace_log().len() == 4);
+        assert!(manager.dropped_events() == 8);
+        // 已经被丢弃的记录不影响栈
+        assert!(manager.func_stack().len() == 12);
+        let bottom = &manager.func_stack()[0];
+        assert!(manager.get_func_from_ins(bottom).unwrap().name == "_start");
+
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        manager.set_clock_type(ClockType::Instret);
+        manager.set_log_limit(LogLimit::Window(10));
+        call(&mut manager, 0x8000, 0x1000);
+        for _ in 0..8 {
+            manager.clock_tick();
+        }
+        call(&mut manager, 0x7f00, 0x1100);
+        assert!(manager.trace_log().len() == 2);
+        for _ in 0..5 {
+            manager.clock_tick();
+        }
+        call(&mut manager, 0x7e00, 0x1200);
+        assert!(manager.trace_log().len() == 2);
+        assert!(manager.get_time_from_index(0) == 8);
+        assert!(manager.dropped_events() == 1);
+
+        // 关闭trace_log之后只剩下栈和sink
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        let sink = Arc::new(std::sync::Mutex::new(VecSink::default()));
+        manager.add_sink(sink.clone());
+        call(&mut manager, 0x8000, 0x1000);
+        manager.set_log_limit(LogLimit::Disabled);
+        assert!(manager.trace_log().is_empty());
+        call(&mut manager, 0x7f00, 0x1100);
+        assert!(manager.mark(MarkerKind::Instant, "check").is_ok());
+        assert!(manager.trace_log().is_empty());
+        assert!(manager.markers().is_empty());
+        assert!(manager.dropped_events() == 2);
+        assert!(manager.func_stack().len() == 2);
+        assert!(sink.lock().unwrap().events.len() == 3);
+    }
+
+    // 失控的递归会产生很深的栈，释放manager时不能递归释放调用链
+    #[test]
+    fn test_deep_stack_drop() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        manager.set_log_limit(LogLimit::Events(16));
+        call(&mut manager, 0x8000_0000, 0x1000);
+        call(&mut manager, 0x7fff_ff00, 0x1100);
+        for idx in 0..200_000 {
+            manager.sync_sp(0x7fff_fe00 - idx * 0x10);
+            manager.jmp_check_add_function(0x1200, Some(0x1110), None);
+        }
+        assert!(manager.func_stack().len() == 200_002);
+        let top = manager.func_stack().last().unwrap().clone();
+        assert!(
+            manager
+                .get_func_from_ins(&top.parent().unwrap())
+                .unwrap()
+                .name
+                == "foo"
+        );
+
+        // 弹出的栈帧被移出trace_log之后就被释放，不会被调用者链留住
+        let bottom = Rc::downgrade(&manager.func_stack()[2]);
+        ret(&mut manager, 0x7fff_ff00, 0x1150);
+        assert!(manager.func_stack().len() == 2);
+        assert!(bottom.upgrade().is_none());
+        drop(manager);
+    }
+
+    #[derive(Default)]
+    struct VecSink {
+        events: Vec<(EventKind, usize, String)>,
+        rets: Vec<Option<String>>,
+    }
+
+    impl super::super::sink::TraceSink for VecSink {
+        fn record(&mut self, event: &TraceEvent) -> std::io::Result<()> {
+            self.events
+                .push((event.kind, event.depth, event.name.clone()));
+            self.rets.push(event.ret.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sink_events() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        let sink = Arc::new(std::sync::Mutex::new(VecSink::default()));
+        manager.add_sink(sink.clone());
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+        // foo被sp跳过，main正常返回
+        ret(&mut manager, 0x7f00, 0x1010);
+        let events = &sink.lock().unwrap().events;
+        assert!(events.len() == 5);
+        assert!(events[0] == (EventKind::Call, 1, "dummy@_start".to_string()));
+        assert!(events[2] == (EventKind::Call, 3, "dummy@foo".to_string()));
+        assert!(events[3] == (EventKind::Unwind, 2, "dummy@foo".to_string()));
+        assert!(events[4] == (EventKind::Return, 1, "dummy@main".to_string()));
+    }
+
+    #[test]
+    fn test_markers() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        let sink = Arc::new(std::sync::Mutex::new(VecSink::default()));
+        manager.add_sink(sink.clone());
+        manager.set_log_limit(LogLimit::Events(2));
+        call(&mut manager, 0x8000, 0x1000);
+        assert!(manager.mark(MarkerKind::Begin, "case1").is_ok());
+        call(&mut manager, 0x7f00, 0x1100);
+        assert!(manager.mark(MarkerKind::Instant, "check").is_ok());
+        // 没有开始的区间不能结束
+        assert!(manager.check_mark(MarkerKind::End, "case2").is_err());
+        assert!(manager.check_mark(MarkerKind::End, "case1").is_ok());
+        assert!(manager.mark(MarkerKind::End, "case2").is_err());
+        assert!(manager.mark(MarkerKind::End, "case1").is_ok());
+        assert!(manager.open_regions().is_empty());
+        let markers = manager.markers();
+        assert!(markers.len() == 3);
+        assert!(markers[0].pos == 1 && markers[0].depth == 1);
+        assert!(markers[1].pos == 2 && markers[1].depth == 2);
+        {
+            let events = &sink.lock().unwrap().events;
+            assert!(events.len() == 5);
+            assert!(events[1] == (EventKind::RegionBegin, 1, "case1".to_string()));
+            assert!(events[4] == (EventKind::RegionEnd, 2, "case1".to_string()));
+        }
+        // 标记随着它前面的记录一起被丢弃
+        call(&mut manager, 0x7e00, 0x1200);
+        call(&mut manager, 0x7d00, 0x1000);
+        assert!(manager.dropped_events() == 2);
+        assert!(manager.markers().len() == 2);
+        assert!(manager.markers()[0].label == "check");
+    }
+
+    #[test]
+    fn test_magic() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        let sink = Arc::new(std::sync::Mutex::new(VecSink::default()));
+        manager.add_sink(sink.clone());
+        manager.set_guest_mem(Some(GuestMem::dummy(0x9000, b"case1\0".to_vec(), 8)));
+        call(&mut manager, 0x8000, 0x1000);
+        assert!(!manager.run_magic(MagicOp::TraceOff, 0));
+        assert!(manager.paused());
+        // 暂停时影子栈照常维护，但是不记录日志
+        call(&mut manager, 0x7f00, 0x1100);
+        assert!(manager.trace_log().len() == 1);
+        assert!(top_name(&manager) == "main");
+        assert!(!manager.run_magic(MagicOp::TraceOn, 0));
+        call(&mut manager, 0x7e00, 0x1200);
+        assert!(manager.trace_log().len() == 2);
+        // 暂停时进入的main没有输出调用，返回时也不输出，事件仍然成对
+        ret(&mut manager, 0x7e00, 0x1108);
+        ret(&mut manager, 0x7f00, 0x1008);
+        {
+            let events = &sink.lock().unwrap().events;
+            let kinds = events.iter().map(|x| x.0).collect::<Vec<_>>();
+            assert!(kinds == [EventKind::Call, EventKind::Call, EventKind::Return]);
+            assert!(events[2].2 == "dummy@foo");
+        }
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+
+        assert!(!manager.run_magic(MagicOp::RegionBegin, 0x9000));
+        assert!(manager.open_regions() == ["case1"]);
+        // 读不到名字时使用地址
+        assert!(!manager.run_magic(MagicOp::Mark, 0x100));
+        assert!(manager.markers()[1].label == "0x100");
+        assert!(manager.run_magic(MagicOp::DumpStack, 0));
+
+        // 暂停时的标记不输出，区间的结束跟随开始是否输出
+        assert!(!manager.run_magic(MagicOp::TraceOff, 0));
+        assert!(!manager.run_magic(MagicOp::Mark, 0x100));
+        assert!(!manager.run_magic(MagicOp::RegionEnd, 0x9000));
+        assert!(!manager.run_magic(MagicOp::RegionBegin, 0x9000));
+        assert!(!manager.run_magic(MagicOp::TraceOn, 0));
+        assert!(!manager.run_magic(MagicOp::RegionEnd, 0x9000));
+        let events = &sink.lock().unwrap().events;
+        let kinds = events
+            .iter()
+            .map(|x| x.0)
+            .filter(|x| !matches!(x, EventKind::Call | EventKind::Return))
+            .collect::<Vec<_>>();
+        assert!(
+            kinds
+                == [
+                    EventKind::RegionBegin,
+                    EventKind::Mark,
+                    EventKind::RegionEnd
+                ]
+        );
+    }
+
+    #[test]
+    fn test_string_args() {
+        use super::super::dwarf::ValueType;
+        let names = ["_start", "printf"];
+        let mut manager = Manager::from_readers(true, dummy_reader("dummy", 0x1000, &names), None);
+        manager.add_signature(
+            "printf".to_string(),
+            FuncSig {
+                params: vec![ValueType::Str],
+                variadic: true,
+                ret: Some(ValueType::Signed(4)),
+            },
+        );
+        let mut data = b"%d\n\0".to_vec();
+        data.extend_from_slice(&[b'x'; 100]);
+        manager.set_guest_mem(Some(GuestMem::dummy(0x9000, data, 8)));
+        manager.set_string_cap(8);
+        call(&mut manager, 0x8000, 0x1000);
+        let call_printf = |manager: &mut Manager, addr: u64| {
+            manager.sync_sp(0x7f00);
+            manager.jmp_check_add_function(0x1100, None, Some(&vec![addr, 42]));
+            let top = manager.func_stack().last().unwrap().clone();
+            let text = manager.format_args(&top).unwrap();
+            manager.sync_sp(0x7f00);
+            manager.ret_pop_function(0x1010, Some((0, None)));
+            text
+        };
+        assert!(call_printf(&mut manager, 0x9000) == "(a0=\"%d\\n\", ...)");
+        // 超过长度限制
+        assert!(call_printf(&mut manager, 0x9004) == "(a0=\"xxxxxxxx\"..., ...)");
+        // 读不到内存时输出地址
+        assert!(call_printf(&mut manager, 0x100) == "(a0=0x100, ...)");
+
+        // 有DWARF签名的函数也使用用户指定的字符串参数
+        let mut reader = dummy_reader("dummy", 0x1000, &names);
+        let dwarf_sig = FuncSig {
+            params: vec![ValueType::Pointer, ValueType::Signed(4)],
+            variadic: false,
+            ret: None,
+        };
+        reader = ElfReader::dummy(
+            0,
+            &reader.name,
+            reader.start,
+            reader.end,
+            Some(
+                reader
+                    .func_vec()
+                    .iter()
+                    .cloned()
+                    .map(|mut func| {
+                        func.sig = Some(dwarf_sig.clone());
+                        func
+                    })
+                    .collect(),
+            ),
+        );
+        let mut manager = Manager::from_readers(true, reader, None);
+        let mut user_sig = FuncSig::untyped(0, false);
+        user_sig.set_string_arg(0);
+        manager.add_signature("printf".to_string(), user_sig);
+        manager.set_guest_mem(Some(GuestMem::dummy(0x9000, b"%d\n\0".to_vec(), 8)));
+        call(&mut manager, 0x8000, 0x1000);
+        assert!(call_printf(&mut manager, 0x9000) == "(a0=\"%d\\n\", a1=42)");
+        let top = manager.func_stack().last().unwrap().clone();
+        assert!(manager.func_sig(&top).unwrap().params[0] == ValueType::Pointer);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let names = ["_start", "main", "foo"];
+        let mut manager = dummy_manager(&names);
+        // 每个函数都保存了ra和帧指针：foo(fp=0x7e00) -> main(fp=0x7f00) -> _start(fp=0x8000)
+        // 帧指针就是CFA，和调用时的sp一致
+        manager.set_guest_mem(Some(GuestMem::dummy_stack(&[
+            (0x7df8, 0x1108),
+            (0x7df0, 0x7f00),
+            (0x7ef8, 0x1008),
+            (0x7ef0, 0x8000),
+        ])));
+        let mut regs = [0; 32];
+        regs[2] = 0x7dc0;
+        regs[8] = 0x7e00;
+        let frames = manager.backtrace(0x1210, &regs);
+        assert!(frames.len() == 3);
+        assert!(manager.pc_name(frames[1].pc) == "dummy@main");
+
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+        assert!(manager.compare_backtrace(&frames).is_empty());
+        // 影子栈漏掉了main，只报告这一帧
+        let mut manager = dummy_manager(&names);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7e00, 0x1200);
+        let diff = manager.compare_backtrace(&frames);
+        assert!(diff == [(1, Some("dummy@main".to_string()), None)]);
+        // 影子栈多出来的外部函数不算不一致
+        let mut manager = dummy_manager(&names);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e80, 0x100);
+        call(&mut manager, 0x7e00, 0x1200);
+        assert!(manager.func_stack().len() == 4);
+        assert!(manager.compare_backtrace(&frames).is_empty());
+    }
+
+    #[test]
+    fn test_attach() {
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        manager.set_guest_mem(Some(GuestMem::dummy_stack(&[
+            (0x7cf8, 0x1108),
+            (0x7cf0, 0x7e00),
+            (0x7df8, 0x1008),
+            (0x7df0, 0x7f00),
+        ])));
+        let mut regs = [0; 32];
+        regs[2] = 0x7cc0;
+        regs[8] = 0x7d00;
+        let sink = Arc::new(std::sync::Mutex::new(VecSink::default()));
+        manager.add_sink(sink.clone());
+        assert!(manager.attach(0x1210, &regs) == Ok(3));
+        assert!(manager.func_stack().len() == 3);
+        assert!(top_name(&manager) == "foo");
+        assert!(manager.attach(0x1210, &regs).is_err());
+        // 从已经在运行的函数返回
+        ret(&mut manager, 0x7d00, 0x1108);
+        assert!(top_name(&manager) == "main");
+        ret(&mut manager, 0x7e00, 0x1008);
+        assert!(top_name(&manager) == "_start");
+        assert!(manager.desync_count() == 0);
+        // 恢复的栈帧没有输出事件，也不算作调用
+        call(&mut manager, 0x7f00, 0x1200);
+        assert!(sink.lock().unwrap().events.len() == 1);
+        let profile = manager.profile();
+        let keys = manager
+            .func_stack()
+            .iter()
+            .map(|x| x.key())
+            .collect::<Vec<_>>();
+        assert!(profile.get(&keys[0]).is_none());
+        assert!(profile.get(&keys[1]).unwrap().calls == 1);
+        assert!(manager.call_graph().edges().len() == 1);
+        // 没有读取内存的回调时只能从当前的函数开始
+        let mut manager = dummy_manager(&["_start", "main", "foo"]);
+        assert!(manager.attach(0x1210, &regs) == Ok(1));
+        assert!(top_name(&manager) == "foo");
+    }
+
+    #[test]
+    fn test_typed_ret() {
+        use super::super::dwarf::ValueType;
+        let names = ["_start", "main", "putch"];
+        let mut manager = Manager::from_readers(true, dummy_reader("dummy", 0x1000, &names), None);
+        manager.add_signature(
+            "main".to_string(),
+            FuncSig {
+                params: Vec::new(),
+                variadic: false,
+                ret: Some(ValueType::Signed(4)),
+            },
+        );
+        manager.add_signature(
+            "putch".to_string(),
+            FuncSig {
+                params: vec![ValueType::Signed(1)],
+                variadic: false,
+                ret: None,
+            },
+        );
+        let sink = Arc::new(std::sync::Mutex::new(VecSink::default()));
+        manager.add_sink(sink.clone());
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+        manager.sync_sp(0x7e00);
+        manager.ret_pop_function(0x1110, Some((0x41, Some(0))));
+        manager.sync_sp(0x7f00);
+        manager.ret_pop_function(0x1010, Some((0xffff_ffff, Some(0))));
+        let rets = &sink.lock().unwrap().rets;
+        // void函数没有返回值
+        assert!(rets[3].is_none());
+        assert!(rets[4].as_deref() == Some("-1"));
+    }
+
+    #[test]
+    fn test_filter_charge_parent() {
+        let mut manager = dummy_manager(&["_start", "main", "memset", "foo"]);
+        manager.set_clock_type(ClockType::Instret);
+        let mut filter = FuncFilter::default();
+        filter.add_func(
+            super::super::filter::NamePattern::new("mem*").unwrap(),
+            false,
+        );
+        manager.set_filter(filter);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        let main = manager.func_stack().last().unwrap().clone();
+        // main -> memset -> foo，memset被过滤
+        manager.jmp_check_add_function(0x1200, Some(0x1110), None);
+        let memset = manager.func_stack().last().unwrap().clone();
+        assert!(memset.hidden());
+        for _ in 0..4 {
+            manager.clock_tick();
+        }
+        manager.sync_sp(0x7e00);
+        manager.jmp_check_add_function(0x1300, Some(0x1210), None);
+        for _ in 0..6 {
+            manager.clock_tick();
+        }
+        ret(&mut manager, 0x7e00, 0x1210);
+        for _ in 0..2 {
+            manager.clock_tick();
+        }
+        ret(&mut manager, 0x7f00, 0x1110);
+        // 影子栈依然正确
+        assert!(manager.func_stack().len() == 2);
+        assert!(Rc::ptr_eq(manager.func_stack().last().unwrap(), &main));
+        // 日志里没有memset
+        assert!(manager.trace_log().iter().all(|x| !x.hidden()));
+
+        let profile = manager.profile();
+        assert!(profile.get(&memset.key()).is_none());
+        // memset自身的6个时间单位算在main上，foo的6个单位不算
+        let main_entry = profile.get(&main.key()).unwrap();
+        assert!(main_entry.inclusive == 12);
+        assert!(main_entry.exclusive == 6);
+        // 调用图中main直接调用foo
+        let call_graph = manager.call_graph();
+        let edges = call_graph.callees_of(&main.key());
+        assert!(edges.len() == 1);
+        assert!(manager.key_name(&edges[0].0) == "foo");
+    }
+
+    #[test]
+    fn test_hooks() {
+        use super::super::hook::{run_pending, HookInfo, HookTarget};
+        let mut manager = dummy_manager(&["_start", "main", "panic", "loader"]);
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let enter_log = log.clone();
+        manager.add_hook(FuncHook {
+            target: HookTarget::parse("panic"),
+            on_enter: Some(Arc::new(move |info: &HookInfo| {
+                enter_log
+                    .lock()
+                    .unwrap()
+                    .push((info.name.to_string(), info.args.map(|x| x[0])));
+                true
+            })),
+            on_exit: None,
+        });
+        let exit_log = log.clone();
+        manager.add_hook(FuncHook {
+            target: HookTarget::parse("0x1300"),
+            on_enter: None,
+            on_exit: Some(Arc::new(move |info: &HookInfo| {
+                exit_log
+                    .lock()
+                    .unwrap()
+                    .push((info.name.to_string(), info.ret_val.map(|x| x.0)));
+                info.ret_val == Some((0, None))
+            })),
+        });
+
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        assert!(!run_pending(manager.take_pending_hooks()));
+        let mut regs = vec![0; MAX_REG_ARGS];
+        regs[0] = 42;
+        manager.sync_sp(0x7e00);
+        manager.jmp_check_add_function(0x1300, Some(0x1110), Some(&regs));
+        assert!(!run_pending(manager.take_pending_hooks()));
+        manager.sync_sp(0x7e00);
+        manager.ret_pop_function(0x1110, Some((0, None)));
+        // 回调在取出之后才执行
+        assert!(log.lock().unwrap().is_empty());
+        // loader返回0，请求停止
+        assert!(run_pending(manager.take_pending_hooks()));
+        assert!(!run_pending(manager.take_pending_hooks()));
+        manager.sync_sp(0x7e00);
+        manager.jmp_check_add_function(0x1200, Some(0x1114), Some(&regs));
+        assert!(run_pending(manager.take_pending_hooks()));
+        assert!(log.lock().unwrap()[0] == ("loader".to_string(), Some(0)));
+        assert!(log.lock().unwrap()[1] == ("panic".to_string(), Some(42)));
+    }
+
+    #[test]
+    fn test_args() {
+        let names = ["_start", "main", "printf", "putch"];
+        let mut manager = Manager::from_readers(true, dummy_reader("dummy", 0x1000, &names), None);
+        manager.add_signature("main".to_string(), FuncSig::untyped(2, false));
+        manager.add_signature("printf".to_string(), FuncSig::untyped(1, true));
+        let args = (1..=MAX_REG_ARGS as u64).collect::<Vec<_>>();
+        let call_args = |manager: &mut Manager, sp: u64, pc: u64| {
+            manager.sync_sp(sp);
+            manager.jmp_check_add_function(pc, None, Some(&args));
+            let top = manager.func_stack().last().unwrap().clone();
+            let len = top.paras().as_ref().map(|x| x.len());
+            (len, manager.format_args(&top))
+        };
+        // 没有签名的函数记录全部8个参数寄存器
+        let (len, text) = call_args(&mut manager, 0x8000, 0x1000);
+        assert!(len == Some(MAX_REG_ARGS));
+        assert!(text.unwrap().starts_with("(a0=0x1, a1=0x2, a2=0x3"));
+        let (len, text) = call_args(&mut manager, 0x7f00, 0x1100);
+        assert!(len == Some(2));
+        assert!(text.as_deref() == Some("(a0=0x1, a1=0x2)"));
+        // 可变参数的函数无法确定个数，全部记录但只显示具名参数
+        let (len, text) = call_args(&mut manager, 0x7e00, 0x1200);
+        assert!(len == Some(MAX_REG_ARGS));
+        assert!(text.as_deref() == Some("(a0=0x1, ...)"));
+
+        // 不需要输出也没有回调时不记录参数
+        let mut manager = dummy_manager(&names);
+        let (len, text) = call_args(&mut manager, 0x8000, 0x1000);
+        assert!(len.is_none() && text.is_none());
+    }
+
+    #[test]
+    fn test_invocations() {
+        let names = ["_start", "main", "foo"];
+        let mut manager = Manager::from_readers(true, dummy_reader("dummy", 0x1000, &names), None);
+        manager.set_clock_type(ClockType::Instret);
+        manager.add_signature("main".to_string(), FuncSig::untyped(1, false));
+        call(&mut manager, 0x8000, 0x1000);
+        manager.clock_tick();
+        manager.sync_sp(0x7f00);
+        manager.jmp_check_add_function(0x1100, None, Some(&vec![7]));
+        manager.clock_tick();
+        manager.sync_sp(0x7f00);
+        manager.ret_pop_function(0x1010, Some((3, None)));
+        manager.clock_tick();
+        call(&mut manager, 0x7f00, 0x1200);
+        manager.clock_tick();
+        // _start在trace_log中出现了两次，但只是一次调用
+        assert!(manager.trace_log().len() == 4);
+        let invocations = manager.invocations();
+        let spans = invocations
+            .iter()
+            .map(|(x, end)| {
+                (
+                    manager.get_func_from_ins(x).unwrap().name.clone(),
+                    x._start_time(),
+                    *end,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert!(spans[0] == ("_start".to_string(), 0, 4));
+        assert!(spans[1] == ("main".to_string(), 1, 2));
+        assert!(spans[2] == ("foo".to_string(), 3, 4));
+        // 弹栈之后依然可以输出参数和返回值
+        let main = &invocations[1].0;
+        assert!(main.paras().is_none());
+        assert!(manager.format_args(main).as_deref() == Some("(a0=0x7)"));
+        assert!(manager.format_ret(main).as_deref() == Some("0x3"));
+    }
+
+    #[test]
+    fn test_load_unload_reader() {
+        let mut manager = dummy_manager(&["_start", "main", "loader"]);
+        let mut hart1 = manager.fork_hart(1);
+        let pal = dummy_reader("pal", 0, &["_start", "pal_main"]).with_base(0x4000_0000);
+        assert!(pal.start == 0x4000_0000);
+        let pal_id = manager.add_prog_reader(pal);
+        assert!(hart1.push_prog_reader(manager.prog_reader(pal_id).unwrap().clone()) == pal_id);
+        assert!(Arc::ptr_eq(
+            manager.prog_reader(pal_id).unwrap(),
+            hart1.prog_reader(pal_id).unwrap()
+        ));
+
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x4000_0100);
+        let pal_main = manager.func_stack().last().unwrap().clone();
+        assert!(top_name(&manager) == "pal_main");
+
+        // loader换上新的程序，旧程序的栈帧依然能找到函数名
+        assert!(manager.unload_prog_reader(pal_id).is_ok());
+        assert!(manager.unload_prog_reader(pal_id).is_err());
+        assert!(manager.unload_prog_reader(5).is_err());
+        let bird_id =
+            manager.add_prog_reader(dummy_reader("bird", 0x4000_0000, &["_start", "bird_main"]));
+        assert!(bird_id == pal_id + 1);
+        assert!(manager.get_func_from_ins(&pal_main).unwrap().name == "pal_main");
+        manager.sync_sp(0x7e00);
+        manager.jmp_check_add_function(0x4000_0100, Some(0x4000_0110), None);
+        assert!(top_name(&manager) == "bird_main");
+        ret(&mut manager, 0x7e00, 0x4000_0110);
+        assert!(Rc::ptr_eq(manager.func_stack().last().unwrap(), &pal_main));
+    }
+
+    #[test]
+    fn test_desync_recover() {
+        let mut manager = dummy_manager(&["_start", "main", "foo", "bar"]);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        call(&mut manager, 0x7e00, 0x1200);
+        // 返回到栈顶的函数自己
+        ret(&mut manager, 0x7e00, 0x1250);
+        assert!(manager.desync_count() == 1);
+        assert!(manager.func_stack().len() == 3);
+        assert!(top_name(&manager) == "foo");
+        // 返回到栈上没有的函数，从bar重建栈
+        ret(&mut manager, 0x7e00, 0x1350);
+        assert!(manager.desync_count() == 2);
+        assert!(manager.func_stack().len() == 1);
+        assert!(top_name(&manager) == "bar");
+        // 之后可以继续正常追踪
+        call(&mut manager, 0x7d00, 0x1100);
+        ret(&mut manager, 0x7d00, 0x1310);
+        assert!(top_name(&manager) == "bar");
+        assert!(manager.desync_count() == 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected behaviour, abort!")]
+    fn test_desync_strict() {
+        let mut manager = dummy_manager(&["_start", "main", "foo", "bar"]);
+        manager.set_desync_policy(DesyncPolicy::Strict);
+        call(&mut manager, 0x8000, 0x1000);
+        call(&mut manager, 0x7f00, 0x1100);
+        ret(&mut manager, 0x7f00, 0x1350);
+    }
+
+    #[test]
+    fn test_fork_hart() {
+        let mut hart0 = dummy_manager(&["_start", "main", "worker"]);
+        let mut hart1 = hart0.fork_hart(1);
+        assert!(hart1.hart() == 1);
+        assert!(Arc::ptr_eq(&hart0.main_reader, &hart1.main_reader));
+
+        call(&mut hart0, 0x8000, 0x1000);
+        call(&mut hart0, 0x7f00, 0x1100);
+        call(&mut hart1, 0x9000, 0x1000);
+        call(&mut hart1, 0x8f00, 0x1200);
+        // 两个hart的栈互不影响
+        assert!(top_name(&hart0) == "main");
+        assert!(top_name(&hart1) == "worker");
+        ret(&mut hart1, 0x9000, 0x1000);
+        assert!(hart0.func_stack().len() == 2);
+        assert!(hart1.func_stack().len() == 1);
+    }
+
     #[test]
     fn test_converter() {
         let manager = Manager::new(
             false,
             "./test_elf/riscv64-nemu-interpreter",
             Some(vec!["./test_elf/nanos-lite-riscv64-nemu.elf"]),
-        );
+        )
+        .unwrap();
         let main_reader = &manager.main_reader;
         let prog_reader = &manager.prog_readers.as_ref().unwrap()[0];
         println!("============To test converter============");
@@ -654,7 +2941,8 @@ mod tests {
             false,
             "./test_elf/riscv64-nemu-interpreter",
             Some(vec!["./test_elf/nanos-lite-riscv64-nemu.elf"]),
-        );
+        )
+        .unwrap();
         let main_reader = &manager.main_reader.clone();
         let prog_reader = &manager.prog_readers.clone().unwrap()[0];
         let file = File::create("./target/log.txt").unwrap();
@@ -662,9 +2950,9 @@ mod tests {
         println!("\n==========================To test add and pop==========================");
         // 测试main reader的函数调用
         for func in main_reader.func_vec().iter().skip(2) {
-            manager.jmp_check_add_function(func.start, None);
+            manager.jmp_check_add_function(func.start, None, None);
             let func_ins = manager.func_stack.last().unwrap();
-            let func_ins1 = manager.trace_log.last().unwrap();
+            let func_ins1 = manager.trace_log.back().unwrap();
             assert!(func_ins.id == func_ins1.id);
             assert!(func_ins.reader == func_ins1.reader);
             if func.func_type == FunType::LocalFunc && func.start != func.end {
@@ -736,8 +3024,8 @@ mod tests {
                     manager.ret_pop_function(func.start, None);
                     assert!(manager.func_stack().last().unwrap().id == func_ins.id);
                     assert!(manager.func_stack().last().unwrap().reader == func_ins.reader);
-                    assert!(manager.trace_log.last().unwrap().id == func_ins.id);
-                    assert!(manager.trace_log.last().unwrap().reader == func_ins.reader);
+                    assert!(manager.trace_log.back().unwrap().id == func_ins.id);
+                    assert!(manager.trace_log.back().unwrap().reader == func_ins.reader);
                 } else {
                     // 这时候我们输入一个在栈中找不到的函数的地址
                     // 理论上来说，它不会弹出这个内容
@@ -746,8 +3034,8 @@ mod tests {
                     assert!(stack_len == manager.func_stack().len());
                     assert!(manager.func_stack().last().unwrap().id == 0);
                     assert!(manager.func_stack().last().unwrap().reader == func_ins.reader);
-                    assert!(manager.trace_log.last().unwrap().id == 0);
-                    assert!(manager.trace_log.last().unwrap().func_type == FunType::ExternalFunc);
+                    assert!(manager.trace_log.back().unwrap().id == 0);
+                    assert!(manager.trace_log.back().unwrap().func_type == FunType::ExternalFunc);
                 }
             }
         }
@@ -787,8 +3075,8 @@ mod tests {
         assert!(stack_len == manager.func_stack().len());
         assert!(manager.func_stack().last().unwrap().id == top_id);
         assert!(manager.func_stack().last().unwrap().reader == Some(CurReader::MainReader));
-        assert!(manager.trace_log.last().unwrap().id == 0);
-        assert!(manager.trace_log.last().unwrap().func_type == FunType::ExternalFunc);
+        assert!(manager.trace_log.back().unwrap().id == 0);
+        assert!(manager.trace_log.back().unwrap().func_type == FunType::ExternalFunc);
 
         // 此时应该弹出到第一个函数，空函数实例也需要弹出
         let func = manager
@@ -815,9 +3103,9 @@ mod tests {
         // 接下来是测试progs reader的调用
         println!("\n==========================Subtest: Progs reader==========================");
         for func in prog_reader.func_vec().iter() {
-            manager.jmp_check_add_function(func.start, None);
+            manager.jmp_check_add_function(func.start, None, None);
             let func_ins = manager.func_stack.last().unwrap();
-            let func_ins1 = manager.trace_log.last().unwrap();
+            let func_ins1 = manager.trace_log.back().unwrap();
             assert!(func_ins.id == func_ins1.id);
             assert!(func_ins.reader == func_ins1.reader);
             if func.func_type == FunType::LocalFunc && func.start != func.end {
@@ -838,36 +3126,36 @@ mod tests {
         pop(&mut manager, range);
 
         // 测试多次调用同一个无法检测的函数prog reader的start
-        manager.jmp_check_add_function(0x80000000, None);
+        manager.jmp_check_add_function(0x80000000, None, None);
         // 此时栈顶应该多一个空函数
         assert!(manager.func_stack().last().unwrap().id == 0);
         assert!(manager.func_stack().last().unwrap().func_type == FunType::ExternalFunc);
-        assert!(manager.trace_log.last().unwrap().id == 0);
-        assert!(manager.trace_log.last().unwrap().func_type == FunType::ExternalFunc);
+        assert!(manager.trace_log.back().unwrap().id == 0);
+        assert!(manager.trace_log.back().unwrap().func_type == FunType::ExternalFunc);
         let stack_len = manager.func_stack().len();
         let log_len = manager.trace_log.len();
-        manager.jmp_check_add_function(0x80000000, None);
-        manager.jmp_check_add_function(0x80000000, None);
-        manager.jmp_check_add_function(0x80000000, None);
+        manager.jmp_check_add_function(0x80000000, None, None);
+        manager.jmp_check_add_function(0x80000000, None, None);
+        manager.jmp_check_add_function(0x80000000, None, None);
         // 这时候都不应该添加新元素
         assert!(manager.func_stack().len() == stack_len);
         assert!(manager.trace_log.len() == log_len);
 
         // 添加一个新元素后测试不在所有reader的函数添加以及其返回
-        manager.jmp_check_add_function(0x800013BC, None);
+        manager.jmp_check_add_function(0x800013BC, None, None);
 
         // 不在所有reader内的函数
-        manager.jmp_check_add_function(0x90000000, None);
+        manager.jmp_check_add_function(0x90000000, None, None);
         // 此时栈顶应该多一个空函数
         assert!(manager.func_stack().last().unwrap().id == 0);
         assert!(manager.func_stack().last().unwrap().func_type == FunType::ExternalFunc);
-        assert!(manager.trace_log.last().unwrap().id == 0);
-        assert!(manager.trace_log.last().unwrap().func_type == FunType::ExternalFunc);
+        assert!(manager.trace_log.back().unwrap().id == 0);
+        assert!(manager.trace_log.back().unwrap().func_type == FunType::ExternalFunc);
         let stack_len = manager.func_stack().len();
         let log_len = manager.trace_log.len();
-        manager.jmp_check_add_function(0x90000000, None);
-        manager.jmp_check_add_function(0x80000000, None);
-        manager.jmp_check_add_function(0x90000000, None);
+        manager.jmp_check_add_function(0x90000000, None, None);
+        manager.jmp_check_add_function(0x80000000, None, None);
+        manager.jmp_check_add_function(0x90000000, None, None);
         // 这时候都不应该添加新元素
         assert!(manager.func_stack().len() == stack_len);
         assert!(manager.trace_log.len() == log_len);
@@ -884,13 +3172,13 @@ mod tests {
         );
         assert!(
             manager
-                .get_func_from_ins(manager.trace_log.last().unwrap())
+                .get_func_from_ins(manager.trace_log.back().unwrap())
                 .unwrap()
                 .name
                 == "memset"
         );
         assert!(manager.func_stack().last().unwrap().func_type == FunType::LocalFunc);
-        assert!(manager.trace_log.last().unwrap().func_type == FunType::LocalFunc);
+        assert!(manager.trace_log.back().unwrap().func_type == FunType::LocalFunc);
 
         print_stack(&manager);
 
@@ -905,7 +3193,7 @@ mod tests {
         );
         assert!(
             manager
-                .get_func_from_ins(manager.trace_log.last().unwrap())
+                .get_func_from_ins(manager.trace_log.back().unwrap())
                 .unwrap()
                 .name
                 == "_start"
@@ -926,8 +3214,8 @@ mod tests {
         // 接下来是压力测试，用于测试大数据下的内存占用
         println!("\n==========================Stress testing==========================");
         for _ in 0..500_000 {
-            manager.jmp_check_add_function(0x800013BC, None);
-            manager.jmp_check_add_function(0x4510, None);
+            manager.jmp_check_add_function(0x800013BC, None, None);
+            manager.jmp_check_add_function(0x4510, None, None);
         }
         println!(
             "Current Log memory used by elements is: {}, memory allocated is: {}",
@@ -0,0 +1,87 @@
+use super::sink::EventKind;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MarkerKind {
+    // 某个时刻的标记
+    Instant,
+    // 区间的开始和结束，按名字配对，可以嵌套
+    Begin,
+    End,
+}
+
+impl MarkerKind {
+    pub fn event_kind(&self) -> EventKind {
+        match self {
+            MarkerKind::Instant => EventKind::Mark,
+            MarkerKind::Begin => EventKind::RegionBegin,
+            MarkerKind::End => EventKind::RegionEnd,
+        }
+    }
+}
+
+// 用户插入到trace_log中的标记，比如区分同一次运行中的不同测试用例
+#[derive(Clone, Debug)]
+pub struct Marker {
+    pub kind: MarkerKind,
+    pub label: String,
+    pub time: u64,
+    pub ctx: u64,
+    // 插入时的栈深度
+    pub depth: usize,
+    // 插入时trace_log中已有的记录数，包括已经被丢弃的，
+    // 标记位于这个位置的记录之前
+    pub pos: u64,
+}
+
+// 按照名字把区间的开始和结束配对，返回(开始, 结束)，结束为None表示还没有结束
+pub fn pair_regions(markers: &[&Marker]) -> Vec<(usize, Option<usize>)> {
+    let mut open: Vec<usize> = Vec::new();
+    let mut regions = Vec::new();
+    for (idx, marker) in markers.iter().enumerate() {
+        match marker.kind {
+            MarkerKind::Begin => open.push(idx),
+            MarkerKind::End => {
+                // 开始已经被丢弃的结束无法配对，直接忽略
+                if let Some(pos) = open.iter().rposition(|x| markers[*x].label == marker.label) {
+                    regions.push((open.remove(pos), Some(idx)));
+                }
+            }
+            MarkerKind::Instant => {}
+        }
+    }
+    regions.extend(open.into_iter().map(|x| (x, None)));
+    regions.sort();
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker(kind: MarkerKind, label: &str, time: u64) -> Marker {
+        Marker {
+            kind,
+            label: label.to_string(),
+            time,
+            ctx: 0,
+            depth: 0,
+            pos: time,
+        }
+    }
+
+    #[test]
+    fn test_pair_regions() {
+        let markers = [
+            marker(MarkerKind::Begin, "case1", 1),
+            marker(MarkerKind::Begin, "inner", 2),
+            marker(MarkerKind::Instant, "check", 3),
+            marker(MarkerKind::End, "case1", 4),
+            marker(MarkerKind::End, "lost", 5),
+            marker(MarkerKind::Begin, "case2", 6),
+        ];
+        let markers = markers.iter().collect::<Vec<_>>();
+        // inner和case2还没有结束，lost没有对应的开始
+        assert!(pair_regions(&markers) == [(0, Some(3)), (1, None), (5, None)]);
+        assert!(MarkerKind::Begin.event_kind().as_str() == "begin");
+    }
+}
@@ -1,20 +1,70 @@
+mod call_graph;
+mod chrome_trace;
+mod clock;
+mod dwarf;
 mod elf_reader;
+mod filter;
+mod folded;
+mod guest_mem;
+mod hook;
+mod magic;
 mod manager;
+mod marker;
+mod perfetto;
+mod profile;
+mod sink;
+mod unwind;
 use bitpattern::bitpattern;
+use clock::ClockType;
 use manager::*;
 use std::collections::HashSet;
-use std::io::Write;
-use std::{cell::RefCell, collections::HashMap, fs::File, rc::Rc, sync::Mutex};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufWriter, Write};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{collections::HashMap, fs::File, rc::Rc};
 
-use self::elf_reader::FunType;
-
-// 这里用了unsafe，实际上我不会在任何多线程来修改这些数据
-// 当然，c语言侧也需要保证是单线程的
+use self::call_graph::CallGraph;
+use self::chrome_trace::{ChromeWriter, Slice};
+use self::dwarf::FuncSig;
+use self::elf_reader::{ElfReader, FunType};
+use self::filter::{FuncFilter, NamePattern};
+use self::folded::{fold, FoldedFrame};
+use self::guest_mem::GuestMem;
+pub use self::guest_mem::MemReadFn;
+use self::hook::{run_pending, FuncHook, HookTarget};
+pub use self::hook::{HookFn, HookInfo};
+use self::magic::{MagicConfig, MAGIC_OP_NUM, SHAMT_NUM};
+use self::marker::pair_regions;
+pub use self::marker::MarkerKind;
+use self::perfetto::PerfettoSink;
+use self::profile::Profiler;
+use self::sink::StreamSink;
+use std::sync::Arc;
 
 struct ManagerBuilder {
     show_context: bool,
     main_path: String,
     progs_path: Option<HashSet<String>>,
+    // 只在某个地址空间内可见的程序，比如共享同一段虚拟地址的用户程序
+    asid_progs_path: Vec<(String, u64)>,
+    nonlocal_exits: Option<HashSet<String>>,
+    clock_type: ClockType,
+    hart_num: usize,
+    log_limit: LogLimit,
+    // 为false时不在内存中保留trace_log，只输出到stream和perfetto
+    keep_log: bool,
+    stream_path: Option<String>,
+    // Perfetto的二进制trace，和stream一样在运行过程中写入
+    perfetto_path: Option<String>,
+    stream_flush_interval: usize,
+    filter: FuncFilter,
+    desync_policy: DesyncPolicy,
+    // 用户提供的函数签名表，按函数名索引
+    signatures: HashMap<String, FuncSig>,
+    string_cap: usize,
+    magic: MagicConfig,
+    // 一开始就暂停，等待客户机用魔数指令打开
+    start_paused: bool,
 }
 
 #[derive(PartialEq, Eq)]
@@ -24,26 +74,112 @@ enum ImmType {
     J,
 }
 
-thread_local! {
-    static G_MANAGER: RefCell<Option<Manager>> = RefCell::new(None);
+// Manager不是Send，因为它用Rc和Cell组织栈帧。这些Rc都由Manager自己创建，
+// 只保存在它的栈、日志和上下文中，任何接口都不会把它们交给Manager之外，
+// 而Manager之间共享的reader、sink、回调都是Arc并且要求Send + Sync。
+// 所以整个Manager连同它的栈帧一起移动到另一个线程是安全的
+struct HartManager(Manager);
+unsafe impl Send for HartManager {}
+
+// 按照hart编号索引，build之前为空。每个hart有自己的锁，不同hart的check_instruction可以并行，
+// 只有build和reset需要外层的写锁
+static G_MANAGERS: RwLock<HartSlots> = RwLock::new(Vec::new());
+
+type HartSlots = Vec<Mutex<HartManager>>;
+
+// 读内存的回调运行时调用者已经持有锁，再加锁会死锁，所以直接拒绝
+fn check_reentry() -> Result<(), isize> {
+    if guest_mem::in_mem_read() {
+        println!("Warning: ftrace can not be called from the memory read callback");
+        Err(-1)
+    } else {
+        Ok(())
+    }
+}
+
+fn read_managers() -> Result<RwLockReadGuard<'static, HartSlots>, isize> {
+    check_reentry()?;
+    Ok(G_MANAGERS.read().unwrap())
+}
+
+fn write_managers() -> Result<RwLockWriteGuard<'static, HartSlots>, isize> {
+    check_reentry()?;
+    Ok(G_MANAGERS.write().unwrap())
+}
+
+fn lock_hart(slot: &Mutex<HartManager>) -> MutexGuard<'_, HartManager> {
+    slot.lock().unwrap()
+}
+
+fn with_manager<T>(
+    hart: usize,
+    f: impl FnOnce(&mut Manager) -> Result<T, isize>,
+) -> Result<T, isize> {
+    let managers = read_managers()?;
+    if let Some(slot) = managers.get(hart) {
+        f(&mut lock_hart(slot).0)
+    } else {
+        println!("Warning: Manager of hart {} is NULL", hart);
+        Err(-1)
+    }
+}
+
+// 输出的时候需要把所有hart的内容合并在一起
+// 总是按照hart编号的顺序加锁，同时锁多个hart的调用之间不会死锁
+fn with_all_managers<T>(f: impl FnOnce(&[&Manager]) -> Result<T, isize>) -> Result<T, isize> {
+    let managers = read_managers()?;
+    if managers.is_empty() {
+        println!("Warning: Manager is NULL");
+        return Err(-1);
+    }
+    let guards = managers.iter().map(lock_hart).collect::<Vec<_>>();
+    let managers = guards.iter().map(|x| &x.0).collect::<Vec<_>>();
+    f(&managers)
+}
+
+// 修改所有hart的配置
+fn with_all_managers_mut<T>(
+    f: impl FnOnce(&mut [&mut Manager]) -> Result<T, isize>,
+) -> Result<T, isize> {
+    let managers = read_managers()?;
+    if managers.is_empty() {
+        println!("Warning: Manager is NULL");
+        return Err(-1);
+    }
+    let mut guards = managers.iter().map(lock_hart).collect::<Vec<_>>();
+    let mut managers = guards.iter_mut().map(|x| &mut x.0).collect::<Vec<_>>();
+    f(&mut managers)
 }
 
 static G_BUILDER: Mutex<Option<ManagerBuilder>> = Mutex::new(None);
 
+// 每次调用都重新开始一个builder，已经build过的manager需要先reset
 pub fn start_builder(main_path: &str) -> Result<(), isize> {
-    static IS_INIT: Mutex<bool> = Mutex::new(false);
-
-    if !(*IS_INIT.lock().unwrap()) {
-        // false就初始化
+    if read_managers()?.is_empty() {
         let mut data = G_BUILDER.lock().unwrap();
         *data = Some(ManagerBuilder {
             show_context: false,
             main_path: main_path.to_string(),
             progs_path: None,
+            asid_progs_path: Vec::new(),
+            nonlocal_exits: None,
+            clock_type: ClockType::WallMillis,
+            hart_num: 1,
+            log_limit: LogLimit::Unbounded,
+            keep_log: true,
+            stream_path: None,
+            perfetto_path: None,
+            stream_flush_interval: 4096,
+            filter: FuncFilter::default(),
+            desync_policy: DesyncPolicy::default(),
+            signatures: HashMap::new(),
+            string_cap: DEFAULT_STRING_CAP,
+            magic: MagicConfig::default(),
+            start_paused: false,
         });
         Ok(())
     } else {
-        println!("Builder is constructed!");
+        println!("Warning: manager is initialized, reset it first!");
         Err(-1)
     }
 }
@@ -76,27 +212,393 @@ pub fn add_prog_path(path: String) -> Result<(), isize> {
     }
 }
 
+pub fn add_prog_path_asid(path: String, asid: u64) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.asid_progs_path.push((path, asid));
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+pub fn set_clock(code: u32) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        if let Some(clock_type) = ClockType::from_code(code) {
+            x.clock_type = clock_type;
+            Ok(())
+        } else {
+            println!("Warning: unknown clock type {}", code);
+            Err(-1)
+        }
+    } else {
+        println!("Warning: current builder is NULL!");
+        Err(-1)
+    }
+}
+
+pub fn set_hart_num(hart_num: usize) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        if hart_num == 0 {
+            println!("Warning: hart num should be greater than 0");
+            return Err(-1);
+        }
+        x.hart_num = hart_num;
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL!");
+        Err(-1)
+    }
+}
+
+// 只保留最近的capacity条记录，0表示不限制
+pub fn set_log_capacity(capacity: usize) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.log_limit = if capacity == 0 {
+            LogLimit::Unbounded
+        } else {
+            LogLimit::Events(capacity)
+        };
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 关闭之后只保留影子栈，调用记录只写到stream和perfetto中，覆盖容量和时间窗口的设置
+pub fn set_keep_log(keep_log: bool) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.keep_log = keep_log;
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 只保留最近window个时间单位内的记录，0表示不限制
+pub fn set_log_window(window: u64) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.log_limit = if window == 0 {
+            LogLimit::Unbounded
+        } else {
+            LogLimit::Window(window)
+        };
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 在运行过程中把call/ret事件实时写入这个文件
+pub fn set_stream_path(path: String) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.stream_path = Some(path);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 在运行过程中把事件写成Perfetto的TracePacket，可以直接用Perfetto UI打开
+pub fn set_perfetto_path(path: String) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.perfetto_path = Some(path);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+pub fn set_stream_flush_interval(interval: usize) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.stream_flush_interval = interval;
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// pattern默认按glob处理，以"re:"开头时按正则表达式处理
+pub fn add_filter_func(pattern: String, include: bool) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        match NamePattern::new(&pattern) {
+            Ok(pattern) => {
+                x.filter.add_func(pattern, include);
+                Ok(())
+            }
+            Err(err) => {
+                println!("Warning: illegal pattern {}: {}", pattern, err);
+                Err(-1)
+            }
+        }
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// reader的名字是ELF文件名去掉扩展名，比如klib
+pub fn add_filter_reader(reader: String, include: bool) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.filter.add_reader(reader, include);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 0表示不限制深度
+pub fn set_filter_depth(max_depth: usize) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.filter.set_max_depth(if max_depth == 0 {
+            None
+        } else {
+            Some(max_depth)
+        });
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 严格模式下栈失去同步时直接panic，否则记录下来并恢复
+pub fn set_strict_mode(strict: bool) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.desync_policy = if strict {
+            DesyncPolicy::Strict
+        } else {
+            DesyncPolicy::Recover
+        };
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 没有调试信息的函数按照这里的参数个数记录参数寄存器
+pub fn add_signature(name: String, arity: usize, variadic: bool) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        // 保留之前通过add_string_arg指定的字符串参数
+        let mut sig = FuncSig::untyped(arity, variadic);
+        if let Some(old) = x.signatures.get(&name) {
+            sig.merge_strings(old);
+        }
+        x.signatures.insert(name, sig);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 把第index个参数当作char*，调用时读取字符串，有调试信息的函数也会生效
+pub fn add_string_arg(name: String, index: usize) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.signatures
+            .entry(name)
+            .or_insert_with(|| FuncSig::untyped(0, false))
+            .set_string_arg(index);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+pub fn set_string_cap(string_cap: usize) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.string_cap = string_cap;
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 识别slli x0, x0, base+N形式的魔数指令，N是操作编号
+pub fn set_magic_hint(base: u32) -> Result<(), isize> {
+    if base > SHAMT_NUM - MAGIC_OP_NUM {
+        println!("Warning: magic hint base {} is out of range", base);
+        return Err(-1);
+    }
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.magic.hint_base = Some(base);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 识别a7等于num的ecall，操作编号放在a1
+pub fn set_magic_ecall(num: u64) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.magic.ecall_num = Some(num);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+pub fn set_magic_dump_path(path: String) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.magic.dump_path = path;
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+pub fn set_start_paused(paused: bool) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        x.start_paused = paused;
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+pub fn add_nonlocal_exit(name: String) -> Result<(), isize> {
+    let mut data = G_BUILDER.lock().unwrap();
+    if let Some(x) = data.as_mut() {
+        // 在默认的longjmp等函数的基础上添加
+        x.nonlocal_exits
+            .get_or_insert_with(|| {
+                DEFAULT_NONLOCAL_EXITS
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect()
+            })
+            .insert(name);
+        Ok(())
+    } else {
+        println!("Warning: current builder is NULL");
+        Err(-1)
+    }
+}
+
+// 刷新所有输出并清空所有hart的状态，builder保留，可以修改之后再次build_builder
+// 注册的回调也会被清空
+pub fn reset() -> Result<(), isize> {
+    let mut managers = write_managers()?;
+    let mut result = Ok(());
+    for slot in managers.iter() {
+        if lock_hart(slot).0.flush_sinks().is_err() {
+            result = Err(-1);
+        }
+    }
+    managers.clear();
+    result
+}
+
+// 在reset的基础上把builder也释放掉
+pub fn destroy() -> Result<(), isize> {
+    check_reentry()?;
+    let result = reset();
+    *G_BUILDER.lock().unwrap() = None;
+    result
+}
+
+// builder在build之后依然保留，reset之后可以再次build
 pub fn build_builder() -> Result<(), isize> {
     // 贼难写这一部分，主要是Manager的接口设计的有问题
     let mut builder = G_BUILDER.lock().unwrap();
     if let Some(builder) = builder.as_mut() {
-        G_MANAGER.with(|f| {
-            let mut manager = f.borrow_mut();
-            if manager.is_none() {
-                let progs_path = builder.progs_path.clone();
-                let manager_new = if let Some(set) = progs_path {
-                    let progs = Some(set.iter().map(|x| x.as_str()).collect::<Vec<&str>>());
-                    Manager::new(builder.show_context, &builder.main_path, progs)
-                } else {
-                    Manager::new(builder.show_context, &builder.main_path, None)
-                };
-                *manager = Some(manager_new);
-                Ok(())
+        let mut managers = write_managers()?;
+        if managers.is_empty() {
+            let progs_path = builder.progs_path.clone();
+            let mut manager_new = if let Some(set) = progs_path {
+                let progs = Some(set.iter().map(|x| x.as_str()).collect::<Vec<&str>>());
+                Manager::new(builder.show_context, &builder.main_path, progs)?
             } else {
-                println!("Warning: manager is initialized!");
-                Err(-1)
+                Manager::new(builder.show_context, &builder.main_path, None)?
+            };
+            if let Some(nonlocal_exits) = builder.nonlocal_exits.clone() {
+                manager_new.set_nonlocal_exits(nonlocal_exits);
             }
-        })
+            for (path, asid) in builder.asid_progs_path.iter() {
+                manager_new.add_prog_reader(ElfReader::new(0, path)?.with_asid(Some(*asid)));
+            }
+            manager_new.set_clock_type(builder.clock_type);
+            if builder.keep_log {
+                manager_new.set_log_limit(builder.log_limit);
+            } else {
+                manager_new.set_log_limit(LogLimit::Disabled);
+            }
+            manager_new.set_filter(builder.filter.clone());
+            manager_new.set_desync_policy(builder.desync_policy);
+            for (name, sig) in builder.signatures.iter() {
+                manager_new.add_signature(name.clone(), sig.clone());
+            }
+            manager_new.set_string_cap(builder.string_cap);
+            manager_new.set_magic(builder.magic.clone());
+            manager_new.set_paused(builder.start_paused);
+            if let Some(path) = builder.stream_path.as_ref() {
+                match StreamSink::new(
+                    path,
+                    builder.stream_flush_interval,
+                    builder.clock_type.unit(),
+                ) {
+                    Ok(sink) => manager_new.add_sink(Arc::new(Mutex::new(sink))),
+                    Err(err) => {
+                        println!("Error: can not open stream file: {}", err);
+                        return Err(-1);
+                    }
+                }
+            }
+            if let Some(path) = builder.perfetto_path.as_ref() {
+                match PerfettoSink::create(path, builder.clock_type, builder.stream_flush_interval)
+                {
+                    Ok(sink) => manager_new.add_sink(Arc::new(Mutex::new(sink))),
+                    Err(err) => {
+                        println!("Error: can not open perfetto file: {}", err);
+                        return Err(-1);
+                    }
+                }
+            }
+            // 其它hart共享同一份reader
+            let forks = (1..builder.hart_num)
+                .map(|hart| manager_new.fork_hart(hart as u32))
+                .collect::<Vec<_>>();
+            managers.push(Mutex::new(HartManager(manager_new)));
+            managers.extend(forks.into_iter().map(|x| Mutex::new(HartManager(x))));
+            Ok(())
+        } else {
+            println!("Warning: manager is initialized!");
+            Err(-1)
+        }
     } else {
         println!("Warning: current builder is NULL");
         Err(-1)
@@ -174,75 +676,564 @@ fn get_imm(inst: u32, imm_type: ImmType) -> u64 {
     }
 }
 
-pub fn check_instruction(pc: u64, inst: u32, regs: &[u64]) {
+// check_instruction处理完一条指令之后模拟器需要做的事
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CheckResult {
+    Continue,
+    // 有回调请求模拟器停下来
+    Stop,
+    // 这条指令是ftrace的魔数指令，模拟器应该把它当作nop跳过
+    Magic,
+}
+
+pub fn check_instruction_hart(
+    hart: usize,
+    pc: u64,
+    inst: u32,
+    regs: &[u64],
+) -> Result<CheckResult, isize> {
     // 这里的pc是当前指令的pc，通过这个来计算出来跳转到的地址
     let target_pc = if bitpattern!("???????_?????_?????_???_?????_11011_11", inst).is_some() {
         // jal
         let immj = get_imm(inst, ImmType::J);
-        (immj as u128 + pc as u128) as u64
+        Some((immj as u128 + pc as u128) as u64)
     } else if bitpattern!("???????_?????_?????_000_?????_11001_11", inst).is_some() {
         // jalr
         let immi = get_imm(inst, ImmType::I);
-        (immi as u128 + regs[bits(inst as u64, 19, 15) as usize] as u128) as u64 & !(bitmask(1))
+        Some(
+            (immi as u128 + regs[bits(inst as u64, 19, 15) as usize] as u128) as u64
+                & !(bitmask(1)),
+        )
     } else {
-        return;
+        None
     };
-    G_MANAGER.with(|elem| {
-        let mut manager = elem.borrow_mut();
-        if let Some(ref mut manager) = *manager {
-            let inst = inst as u64;
-            if (bits(inst, 19, 15) == 1) && (bits(inst, 11, 7) == 0) {
-                // 首先判断是否是return
-                // riscv用x10和x11返回值
-                manager.ret_pop_function(target_pc, Some((regs[10], Some(regs[11]))));
+    // 每条指令都会调用，所以找不到manager的时候不打印信息
+    let managers = read_managers()?;
+    if let Some(slot) = managers.get(hart) {
+        let mut guard = lock_hart(slot);
+        let manager = &mut guard.0;
+        // 每条指令都要推进指令计数的时钟
+        manager.clock_tick();
+        if let Some(op) = manager.magic().decode(inst, regs) {
+            if manager.run_magic(op, regs[10]) {
+                let path = manager.magic().dump_path.clone();
+                // print_stack需要重新获取所有的manager
+                drop(guard);
+                drop(managers);
+                // 输出失败也要让模拟器跳过魔数指令，不能当作真正的ecall执行
+                if print_stack(path.clone()).is_err() {
+                    println!("Warning: can not dump stack to {}", path);
+                }
+            }
+            return Ok(CheckResult::Magic);
+        }
+        let Some(target_pc) = target_pc else {
+            return Ok(CheckResult::Continue);
+        };
+        // riscv用x2作为sp，先用它把被longjmp等越过的栈帧弹出
+        manager.sync_sp(regs[2]);
+        let inst = inst as u64;
+        if (bits(inst, 19, 15) == 1) && (bits(inst, 11, 7) == 0) {
+            // 首先判断是否是return
+            // riscv用x10和x11返回值
+            manager.ret_pop_function(target_pc, Some((regs[10], Some(regs[11]))));
+        } else {
+            // 只记录a0到a7这8个参数寄存器，manager再按照函数的参数个数截断
+            let args = regs[10..18].to_vec();
+            // rd不为x0时是调用，返回地址就是下一条指令
+            let ret_addr = if bits(inst, 11, 7) != 0 {
+                Some(pc.wrapping_add(4))
             } else {
-                // 这里对于Paras的参数设计有问题，应该直接要求顶层传入有所有权的内容
-                // 只能降低效率了
-                let regs = regs.to_owned();
-                manager.jmp_check_add_function(target_pc, Some(&regs));
+                None
+            };
+            manager.jmp_check_add_function(target_pc, ret_addr, Some(&args));
+        }
+        // 回调中可以再调用ftrace的接口，所以释放锁之后再执行
+        let pending = manager.take_pending_hooks();
+        drop(guard);
+        drop(managers);
+        if run_pending(pending) {
+            // 模拟器停下来之后可能直接退出，先把sink里的事件写入文件
+            if flush_trace().is_err() {
+                println!("Warning: can not flush trace before stop");
             }
+            Ok(CheckResult::Stop)
+        } else {
+            Ok(CheckResult::Continue)
         }
-    });
+    } else {
+        Err(-1)
+    }
+}
+
+pub fn switch_context(hart: usize, ctx: u64) -> Result<(), isize> {
+    with_manager(hart, |manager| {
+        manager.switch_context(ctx);
+        Ok(())
+    })
+}
+
+// 在所有hart上注册进入和离开函数时的回调，target可以是函数名，也可以是0x开头的函数起始地址
+pub fn register_hook(
+    target: &str,
+    on_enter: Option<HookFn>,
+    on_exit: Option<HookFn>,
+) -> Result<(), isize> {
+    if on_enter.is_none() && on_exit.is_none() {
+        println!("Warning: hook for {} has no callback", target);
+        return Err(-1);
+    }
+    let hook = FuncHook {
+        target: HookTarget::parse(target),
+        on_enter,
+        on_exit,
+    };
+    with_all_managers_mut(|managers| {
+        for manager in managers.iter_mut() {
+            manager.add_hook(hook.clone());
+        }
+        Ok(())
+    })
+}
+
+// 在所有hart的trace_log中插入标记，每个hart使用自己的时间和栈深度
+pub fn mark(kind: MarkerKind, label: &str) -> Result<(), isize> {
+    with_all_managers_mut(|managers| {
+        // 先在所有hart上检查，避免结束区间只在一部分hart上生效
+        let mut checked = true;
+        for manager in managers.iter() {
+            if manager.check_mark(kind, label).is_err() {
+                checked = false;
+            }
+        }
+        if !checked {
+            return Err(-1);
+        }
+        for manager in managers.iter_mut() {
+            manager.mark(kind, label)?;
+        }
+        Ok(())
+    })
+}
+
+// 在所有hart上设置读取客户机内存的回调，None表示取消
+pub fn set_mem_reader(read: Option<MemReadFn>) -> Result<(), isize> {
+    let guest_mem = read.map(GuestMem::new);
+    with_all_managers_mut(|managers| {
+        for manager in managers.iter_mut() {
+            manager.set_guest_mem(guest_mem.clone());
+        }
+        Ok(())
+    })
+}
+
+// 在build之后加载新的程序，所有hart共享同一个reader，返回reader的id
+// asid为None表示在所有地址空间都可见
+pub fn load_elf(path: String, base: u64, asid: Option<u64>) -> Result<u32, isize> {
+    // 解析ELF比较慢，在加锁之前完成
+    let reader = ElfReader::new(0, &path)?.with_base(base).with_asid(asid);
+    with_all_managers_mut(|managers| {
+        let (first, others) = managers.split_first_mut().expect("Managers are not empty");
+        let id = first.add_prog_reader(reader);
+        let reader = first
+            .prog_reader(id)
+            .expect("Reader should be loaded")
+            .clone();
+        for manager in others.iter_mut() {
+            manager.push_prog_reader(reader.clone());
+        }
+        Ok(id)
+    })
+}
+
+pub fn unload_elf(id: u32) -> Result<(), isize> {
+    with_all_managers_mut(|managers| {
+        let mut result = Ok(());
+        for manager in managers.iter_mut() {
+            if manager.unload_prog_reader(id).is_err() {
+                result = Err(-1);
+            }
+        }
+        result
+    })
+}
+
+// 所有hart从失去同步中恢复的总次数
+pub fn desync_count() -> Result<u64, isize> {
+    with_all_managers(|managers| Ok(managers.iter().map(|x| x.desync_count()).sum()))
+}
+
+// 用satp中的ASID区分上下文，和add_prog_path_asid的asid一致，bare模式下都是0号上下文
+// 操作系统需要给每个进程分配不同的ASID，ASID为0的进程和bare模式共用0号上下文
+fn satp_context(satp: u64) -> u64 {
+    if bits(satp, 63, 60) == 0 {
+        0
+    } else {
+        // Sv39和Sv48的ASID都在satp[59:44]
+        bits(satp, 59, 44)
+    }
+}
+
+pub fn set_satp(hart: usize, satp: u64) -> Result<(), isize> {
+    switch_context(hart, satp_context(satp))
+}
+
+// 对于Cycle和Mtime时钟，需要调用者在每次check_instruction之前提供时间
+pub fn set_time(hart: usize, value: u64) -> Result<(), isize> {
+    with_manager(hart, |manager| manager.set_time(value))
+}
+
+// 把还在缓冲区里的事件写入文件，模拟器退出之前调用
+pub fn flush_trace() -> Result<(), isize> {
+    with_all_managers(|managers| {
+        let mut result = Ok(());
+        for manager in managers.iter() {
+            if manager.flush_sinks().is_err() {
+                result = Err(-1);
+            }
+        }
+        result
+    })
 }
 
 pub fn print_stack(path: String) -> Result<(), isize> {
-    G_MANAGER.with(|elem| {
-        if let Some(ref mut manager) = *elem.borrow_mut() {
-            let file = File::create(path);
-            if let Ok(mut file) = file {
-                let stack = manager.func_stack();
-                let stack_iter = stack
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, elem)| (stack.len() - (idx + 1), elem))
-                    .rev();
+    with_all_managers(|managers| {
+        let file = File::create(path);
+        if let Ok(mut file) = file {
+            writeln!(
+                file,
+                "========================STACK TRACE========================"
+            )
+            .unwrap();
+            for manager in managers.iter() {
+                // 只有一个hart的时候保持原来的格式
+                if managers.len() > 1 {
+                    writeln!(file, "============hart: {}============", manager.hart()).unwrap();
+                }
+                if manager.desync_count() > 0 {
+                    writeln!(file, "desync recovered: {}", manager.desync_count()).unwrap();
+                }
+                if !manager.open_regions().is_empty() {
+                    writeln!(file, "open regions: {}", manager.open_regions().join(", ")).unwrap();
+                }
+                let stacks = manager.stacks();
+                for (ctx, stack) in stacks.iter() {
+                    // 只有一个上下文的时候保持原来的格式
+                    if stacks.len() > 1 {
+                        let current = if *ctx == manager.ctx() {
+                            " (current)"
+                        } else {
+                            ""
+                        };
+                        writeln!(file, "------------context: {}{}------------", ctx, current)
+                            .unwrap();
+                    }
+                    // 被过滤的函数不输出
+                    let stack = stack.iter().filter(|x| !x.hidden()).collect::<Vec<_>>();
+                    let stack_iter = stack
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, elem)| (stack.len() - (idx + 1), elem))
+                        .rev();
+                    for (idx, elem) in stack_iter {
+                        let func = manager.get_func_from_ins(elem);
+                        if let Some(func) = func {
+                            let args = manager.format_args(elem).unwrap_or_default();
+                            writeln!(
+                                file,
+                                "@{}, function: {}{}, start: {}, end: {} ",
+                                idx, func.name, args, func.start, func.end
+                            )
+                            .unwrap();
+                        } else {
+                            writeln!(file, "@{}, function: unknown", idx).unwrap();
+                        }
+                    }
+                }
+            }
+            Ok(())
+        } else {
+            println!("Error: can not open file");
+            Err(-1)
+        }
+    })
+}
+
+// 在运行中途开始追踪，用当前的寄存器回溯出还在运行的函数，返回压栈的栈帧数
+pub fn attach(hart: usize, pc: u64, regs: &[u64]) -> Result<usize, isize> {
+    with_manager(hart, |manager| manager.attach(pc, regs))
+}
+
+// 用CFI或者帧指针回溯hart当前的调用栈，并列出和影子栈不一致的地方
+pub fn print_backtrace(hart: usize, pc: u64, regs: &[u64], path: String) -> Result<(), isize> {
+    with_manager(hart, |manager| {
+        let file = File::create(path);
+        if let Ok(mut file) = file {
+            let frames = manager.backtrace(pc, regs);
+            writeln!(
+                file,
+                "========================BACKTRACE========================"
+            )
+            .unwrap();
+            for (idx, frame) in frames.iter().enumerate() {
+                writeln!(
+                    file,
+                    "#{}, pc: {:#x}, sp: {:#x}, function: {} [{}]",
+                    idx,
+                    frame.pc,
+                    frame.sp,
+                    manager.pc_name(frame.pc),
+                    frame.method.as_str()
+                )
+                .unwrap();
+            }
+            writeln!(
+                file,
+                "====================SHADOW STACK DIFF===================="
+            )
+            .unwrap();
+            let diff = manager.compare_backtrace(&frames);
+            if diff.is_empty() {
+                writeln!(file, "shadow stack agrees with the unwinder").unwrap();
+            }
+            for (idx, unwound, shadow) in diff {
+                writeln!(
+                    file,
+                    "#{}, unwound: {}, shadow: {}",
+                    idx,
+                    unwound.as_deref().unwrap_or("-"),
+                    shadow.as_deref().unwrap_or("-")
+                )
+                .unwrap();
+            }
+            Ok(())
+        } else {
+            println!("Error: can not open file");
+            Err(-1)
+        }
+    })
+}
+
+pub fn print_profile(path: String) -> Result<(), isize> {
+    with_all_managers(|managers| {
+        let file = File::create(path);
+        if let Ok(mut file) = file {
+            // 所有hart共享reader，所以用0号hart解析函数名
+            let manager = managers[0];
+            let mut profile = Profiler::default();
+            for elem in managers.iter() {
+                profile.merge(&elem.profile());
+            }
+            let total = profile.total_exclusive();
+            writeln!(
+                file,
+                "========================FLAT PROFILE========================"
+            )
+            .unwrap();
+            writeln!(file, "time unit: {}", manager.clock().clock_type().unit()).unwrap();
+            writeln!(
+                file,
+                "{:>7} {:>14} {:>14} {:>10} {:>14} {:>12} {:>12} {:>14}  name",
+                "%time", "self", "cumulative", "calls", "total", "min", "max", "mean"
+            )
+            .unwrap();
+            let mut cumulative = 0;
+            for (key, entry) in profile.sorted() {
+                cumulative += entry.exclusive;
+                let percent = if total == 0 {
+                    0_f64
+                } else {
+                    entry.exclusive as f64 * 100_f64 / total as f64
+                };
+                writeln!(
+                    file,
+                    "{:>7.2} {:>14} {:>14} {:>10} {:>14} {:>12} {:>12} {:>14.2}  {}@{}",
+                    percent,
+                    entry.exclusive,
+                    cumulative,
+                    entry.calls,
+                    entry.inclusive,
+                    entry.min,
+                    entry.max,
+                    entry.mean(),
+                    manager.key_reader_name(&key),
+                    manager.key_name(&key)
+                )
+                .unwrap();
+            }
+            Ok(())
+        } else {
+            println!("Error: can not open file");
+            Err(-1)
+        }
+    })
+}
+
+pub fn print_call_graph(path: String) -> Result<(), isize> {
+    with_all_managers(|managers| {
+        let file = File::create(path);
+        if let Ok(mut file) = file {
+            let manager = managers[0];
+            let mut call_graph = CallGraph::default();
+            for elem in managers.iter() {
+                call_graph.merge(&elem.call_graph());
+            }
+            writeln!(
+                file,
+                "========================CALL GRAPH========================"
+            )
+            .unwrap();
+            writeln!(file, "time unit: {}", manager.clock().clock_type().unit()).unwrap();
+            writeln!(file, "{:>10} {:>14}  caller -> callee", "calls", "total").unwrap();
+            for (caller, callee, edge) in call_graph.edges() {
                 writeln!(
                     file,
-                    "========================STACK TRACE========================"
+                    "{:>10} {:>14}  {}@{} -> {}@{}",
+                    edge.calls,
+                    edge.inclusive,
+                    manager.key_reader_name(&caller),
+                    manager.key_name(&caller),
+                    manager.key_reader_name(&callee),
+                    manager.key_name(&callee)
                 )
                 .unwrap();
-                for (idx, elem) in stack_iter {
-                    let func = manager.get_func_from_ins(elem);
-                    if let Some(func) = func {
-                        writeln!(
-                            file,
-                            "@{}, function: {}, start: {}, end: {} ",
-                            idx, func.name, func.start, func.end
+            }
+            Ok(())
+        } else {
+            println!("Error: can not open file");
+            Err(-1)
+        }
+    })
+}
+
+// 导出为Chrome Trace Event Format的JSON，可以用chrome://tracing或者Perfetto UI打开
+pub fn print_chrome_trace(path: String) -> Result<(), isize> {
+    with_all_managers(|managers| {
+        let Ok(file) = File::create(path) else {
+            println!("Error: can not open file");
+            return Err(-1);
+        };
+        write_chrome_trace(managers, BufWriter::new(file)).map_err(|err| {
+            println!("Error: can not write chrome trace: {}", err);
+            -1
+        })
+    })
+}
+
+fn write_chrome_trace(managers: &[&Manager], out: impl Write) -> io::Result<()> {
+    let clock_type = managers[0].clock().clock_type();
+    let nanos = |time: u64| clock_type.to_nanos(time);
+    let mut writer = ChromeWriter::new(out)?;
+    for manager in managers.iter() {
+        let hart = manager.hart();
+        writer.process_name(hart, &format!("hart {}", hart))?;
+        let invocations = manager.invocations();
+        // 上下文可能是很大的值，按照顺序编号作为tid
+        let tids = invocations
+            .iter()
+            .map(|(func_ins, _)| func_ins.ctx())
+            .chain(manager.markers().iter().map(|x| x.ctx))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .enumerate()
+            .map(|(tid, ctx)| (ctx, tid as u32))
+            .collect::<BTreeMap<_, _>>();
+        for (ctx, tid) in tids.iter() {
+            writer.thread_name(hart, *tid, &format!("context {:#x}", ctx))?;
+        }
+        for (func_ins, end) in invocations {
+            let key = func_ins.key();
+            let mut args = Vec::new();
+            if let Some(text) = manager.format_args(&func_ins) {
+                args.push(("args", text));
+            }
+            if let Some(text) = manager.format_ret(&func_ins) {
+                args.push(("ret", text));
+            }
+            if func_ins.unwound() {
+                args.push(("unwound", "true".to_string()));
+            }
+            let start = func_ins._start_time();
+            writer.complete(&Slice {
+                name: &manager.key_name(&key),
+                cat: &manager.key_reader_name(&key),
+                hart,
+                tid: tids[&func_ins.ctx()],
+                start: nanos(start),
+                dur: nanos(end.saturating_sub(start)),
+                args: &args,
+            })?;
+        }
+        let markers = manager.markers().iter().collect::<Vec<_>>();
+        for marker in markers.iter().filter(|x| x.kind == MarkerKind::Instant) {
+            writer.instant(hart, tids[&marker.ctx], &marker.label, nanos(marker.time))?;
+        }
+        // 还没有结束的区间画到现在
+        let now = manager.get_time();
+        for (id, (begin, end)) in pair_regions(&markers).into_iter().enumerate() {
+            let end = end.map_or(now, |x| markers[x].time);
+            let marker = markers[begin];
+            writer.region(hart, id, &marker.label, nanos(marker.time), nanos(end))?;
+        }
+    }
+    writer.finish(clock_type.unit())?;
+    Ok(())
+}
+
+// 输出Brendan Gregg的折叠栈格式，权重是自身时间，可以直接交给flamegraph.pl
+pub fn print_folded(path: String) -> Result<(), isize> {
+    with_all_managers(|managers| {
+        let Ok(file) = File::create(path) else {
+            println!("Error: can not open file");
+            return Err(-1);
+        };
+        let mut file = BufWriter::new(file);
+        let mut folded = BTreeMap::new();
+        for manager in managers.iter() {
+            // 只有一个程序的时候不加reader的前缀
+            let with_reader = manager.reader_count() > 1;
+            let invocations = manager.invocations();
+            let index = invocations
+                .iter()
+                .enumerate()
+                .map(|(idx, (func_ins, _))| (Rc::as_ptr(func_ins), idx))
+                .collect::<HashMap<_, _>>();
+            let frames = invocations
+                .iter()
+                .map(|(func_ins, end)| {
+                    let key = func_ins.key();
+                    let name = if with_reader {
+                        format!(
+                            "{}@{}",
+                            manager.key_reader_name(&key),
+                            manager.key_name(&key)
                         )
-                        .unwrap();
                     } else {
-                        writeln!(file, "@{}, function: unknown", idx).unwrap();
+                        manager.key_name(&key)
+                    };
+                    FoldedFrame {
+                        parent: func_ins
+                            .visible_parent()
+                            .and_then(|x| index.get(&Rc::as_ptr(&x)).copied()),
+                        name,
+                        duration: end.saturating_sub(func_ins._start_time()),
                     }
-                }
-                Ok(())
-            } else {
-                println!("Error: can not open file");
-                Err(-1)
+                })
+                .collect::<Vec<_>>();
+            for (stack, weight) in fold(&frames) {
+                // 只有一个hart的时候保持原来的格式
+                let stack = if managers.len() > 1 {
+                    format!("hart {};{}", manager.hart(), stack)
+                } else {
+                    stack
+                };
+                *folded.entry(stack).or_insert(0) += weight;
             }
-        } else {
-            println!("Warning: Manager is NULL");
-            Err(-1)
         }
+        for (stack, weight) in folded {
+            writeln!(file, "{} {}", stack, weight).unwrap();
+        }
+        Ok(())
     })
 }
 
@@ -312,24 +1303,47 @@ fn print_oneline(mut file: &File, manager: &Manager, vec_item: LogTransItem) {
 
 #[allow(dead_code)]
 pub fn print_log(path: String) {
-    G_MANAGER.with(|elem| {
-        if let Some(ref mut manager) = *elem.borrow_mut() {
-            let file = File::create(path);
-            if let Ok(file) = file {
-                let log_vec = log_translation(manager);
-                let end_time = manager.get_time_base_end() as f64;
-                let scale: f64 = end_time / 10000_f64; // 以分成10000份为基准
-                print_scale(&file, scale);
-                for item in log_vec {
-                    print_oneline(&file, manager, item);
-                }
-            } else {
-                println!("Error: can not open file");
+    let _ = with_all_managers(|managers| {
+        let file = File::create(path);
+        if let Ok(mut file) = file {
+            // 所有hart使用同一个时基，合并到一条时间线上
+            let end_time = managers
+                .iter()
+                .map(|x| x.get_time_base_end())
+                .max()
+                .unwrap_or(0) as f64;
+            let scale: f64 = end_time / 10000_f64; // 以分成10000份为基准
+            print_scale(&file, scale);
+            // 开启了环形缓冲区时，最早的记录已经被丢弃
+            for manager in managers.iter().filter(|x| x.dropped_events() > 0) {
+                writeln!(
+                    file,
+                    "hart {}: {} earlier events dropped",
+                    manager.hart(),
+                    manager.dropped_events()
+                )
+                .unwrap();
+            }
+            let mut log_vec = managers
+                .iter()
+                .flat_map(|manager| {
+                    log_translation(manager)
+                        .into_iter()
+                        .map(move |item| (*manager, item))
+                })
+                .collect::<Vec<_>>();
+            log_vec.sort_by_key(|(manager, (_, time_vec))| {
+                (time_vec.first().map(|x| x.0), manager.hart())
+            });
+            for (manager, item) in log_vec {
+                print_oneline(&file, manager, item);
             }
+            Ok(())
         } else {
-            println!("Warning: Manager is NULL");
+            println!("Error: can not open file");
+            Err(-1)
         }
-    })
+    });
 }
 
 #[allow(dead_code)]
@@ -362,6 +1376,7 @@ mod test {
 
     #[test]
     #[should_panic]
+    #[allow(clippy::same_item_push)]
     fn test_target_pc_gen() {
         let mut vec = Vec::new();
         for _ in 0..64 {
@@ -370,6 +1385,48 @@ mod test {
         target_pc_gen(0, 0xfce040e3, &vec);
     }
 
+    // 只有这个测试会使用全局的manager
+    #[test]
+    fn test_lifecycle() {
+        let exe = std::env::current_exe().unwrap();
+        let exe = exe.to_str().unwrap();
+        assert!(build_builder().is_err());
+        assert!(start_builder(exe).is_ok());
+        // 所有的操作编号都要在shamt的范围内，过大的base不能溢出
+        assert!(set_magic_hint(SHAMT_NUM - MAGIC_OP_NUM).is_ok());
+        assert!(set_magic_hint(SHAMT_NUM - MAGIC_OP_NUM + 1).is_err());
+        assert!(set_magic_hint(u32::MAX).is_err());
+        assert!(set_hart_num(2).is_ok());
+        assert!(build_builder().is_ok());
+        assert!(build_builder().is_err());
+        assert!(start_builder(exe).is_err());
+        assert!(switch_context(1, 0).is_ok());
+        assert!(switch_context(2, 0).is_err());
+
+        // reset之后可以用同样的配置再次build
+        assert!(reset().is_ok());
+        assert!(switch_context(0, 0).is_err());
+        assert!(build_builder().is_ok());
+        assert!(switch_context(1, 0).is_ok());
+
+        assert!(destroy().is_ok());
+        assert!(switch_context(0, 0).is_err());
+        assert!(build_builder().is_err());
+        assert!(start_builder(exe).is_ok());
+        assert!(build_builder().is_ok());
+        assert!(switch_context(1, 0).is_err());
+        assert!(destroy().is_ok());
+    }
+
+    #[test]
+    fn test_satp_context() {
+        assert!(satp_context(0x0000_0000_0008_0000) == 0);
+        // MODE和PPN不影响上下文
+        assert!(satp_context(0x8000_1000_0008_0000) == 1);
+        assert!(satp_context(0x9000_1000_0008_1234) == 1);
+        assert!(satp_context(0x8fff_f000_0000_0000) == 0xffff);
+    }
+
     #[test]
     fn test_print_scale() {
         let file = File::create("./target/1.txt").unwrap();
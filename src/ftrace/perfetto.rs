@@ -0,0 +1,427 @@
+use super::clock::ClockType;
+use super::sink::{EventKind, TraceEvent, TraceSink};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Perfetto的protobuf字段编号，只用到了其中很少的一部分
+// Trace
+const TRACE_PACKET: u32 = 1;
+// TracePacket
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_INTERNED_DATA: u32 = 12;
+const PACKET_SEQUENCE_FLAGS: u32 = 13;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+// TrackDescriptor
+const TRACK_UUID: u32 = 1;
+const TRACK_NAME: u32 = 2;
+const TRACK_PARENT_UUID: u32 = 5;
+const TRACK_COUNTER: u32 = 8;
+// TrackEvent
+const EVENT_DEBUG_ANNOTATIONS: u32 = 4;
+const EVENT_TYPE: u32 = 9;
+const EVENT_NAME_IID: u32 = 10;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_COUNTER_VALUE: u32 = 30;
+// DebugAnnotation
+const ANNOTATION_STRING_VALUE: u32 = 6;
+const ANNOTATION_NAME: u32 = 10;
+// InternedData和EventName
+const INTERNED_EVENT_NAMES: u32 = 2;
+const EVENT_NAME_IID_FIELD: u32 = 1;
+const EVENT_NAME_NAME: u32 = 2;
+
+// TrackEvent.Type
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+// TracePacket.SequenceFlags
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u64 = 2;
+
+// 所有的packet都在同一个序列中，多个hart共享同一个sink
+const SEQUENCE_ID: u64 = 1;
+
+// 手写的protobuf编码，只支持varint和length-delimited
+#[derive(Default)]
+struct Proto {
+    buf: Vec<u8>,
+}
+
+impl Proto {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn varint(&mut self, field: u32, value: u64) -> &mut Self {
+        self.raw_varint((field as u64) << 3);
+        self.raw_varint(value);
+        self
+    }
+
+    fn bytes(&mut self, field: u32, data: &[u8]) -> &mut Self {
+        self.raw_varint(((field as u64) << 3) | 2);
+        self.raw_varint(data.len() as u64);
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    fn string(&mut self, field: u32, text: &str) -> &mut Self {
+        self.bytes(field, text.as_bytes())
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut Proto)) -> &mut Self {
+        let mut sub = Proto::default();
+        build(&mut sub);
+        self.bytes(field, &sub.buf)
+    }
+}
+
+// 每个hart一个轨道，下面是每个上下文，再下面是每个reader，
+// 一个调用栈按照reader拆开之后在每个轨道上依然是正确嵌套的
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum TrackKey {
+    Hart(u32),
+    Context(u32, u64),
+    Reader(u32, u64, String),
+    // 栈深度的计数器
+    Depth(u32, u64),
+    // 区间之间可能交错，每个名字一个轨道
+    Region(u32, String),
+}
+
+impl TrackKey {
+    fn parent(&self) -> Option<TrackKey> {
+        match self {
+            TrackKey::Hart(_) => None,
+            TrackKey::Context(hart, _) | TrackKey::Region(hart, _) => Some(TrackKey::Hart(*hart)),
+            TrackKey::Reader(hart, ctx, _) | TrackKey::Depth(hart, ctx) => {
+                Some(TrackKey::Context(*hart, *ctx))
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            TrackKey::Hart(hart) => format!("hart {}", hart),
+            TrackKey::Context(_, ctx) => format!("context {}", ctx),
+            TrackKey::Reader(_, _, reader) => reader.clone(),
+            TrackKey::Depth(_, _) => "stack depth".to_string(),
+            TrackKey::Region(_, label) => format!("region {}", label),
+        }
+    }
+}
+
+// 把manager的事件直接编码为Perfetto的TracePacket，函数名通过interned data只写一次，
+// 和StreamSink一样每flush_interval个事件刷新一次
+pub struct PerfettoSink<W: Write> {
+    writer: W,
+    clock_type: ClockType,
+    flush_interval: usize,
+    pending: usize,
+    tracks: HashMap<TrackKey, u64>,
+    names: HashMap<String, u64>,
+}
+
+impl PerfettoSink<BufWriter<File>> {
+    pub fn create(path: &str, clock_type: ClockType, flush_interval: usize) -> io::Result<Self> {
+        PerfettoSink::new(
+            BufWriter::new(File::create(path)?),
+            clock_type,
+            flush_interval,
+        )
+    }
+}
+
+impl<W: Write> PerfettoSink<W> {
+    pub fn new(writer: W, clock_type: ClockType, flush_interval: usize) -> io::Result<Self> {
+        let mut sink = PerfettoSink {
+            writer,
+            clock_type,
+            flush_interval: flush_interval.max(1),
+            pending: 0,
+            tracks: HashMap::new(),
+            names: HashMap::new(),
+        };
+        // 清空增量状态之后才能使用interned data
+        let mut packet = Proto::default();
+        packet
+            .varint(PACKET_SEQUENCE_ID, SEQUENCE_ID)
+            .varint(PACKET_SEQUENCE_FLAGS, SEQ_INCREMENTAL_STATE_CLEARED);
+        sink.write_packet(&packet)?;
+        sink.writer.flush()?;
+        Ok(sink)
+    }
+
+    fn write_packet(&mut self, packet: &Proto) -> io::Result<()> {
+        let mut trace = Proto::default();
+        trace.bytes(TRACE_PACKET, &packet.buf);
+        self.writer.write_all(&trace.buf)
+    }
+
+    // 第一次使用轨道时先写出它和它的祖先的描述
+    fn track(&mut self, key: &TrackKey) -> io::Result<u64> {
+        if let Some(uuid) = self.tracks.get(key) {
+            return Ok(*uuid);
+        }
+        let parent = match key.parent() {
+            Some(parent) => Some(self.track(&parent)?),
+            None => None,
+        };
+        let uuid = self.tracks.len() as u64 + 1;
+        self.tracks.insert(key.clone(), uuid);
+        let mut packet = Proto::default();
+        packet.message(PACKET_TRACK_DESCRIPTOR, |desc| {
+            desc.varint(TRACK_UUID, uuid)
+                .string(TRACK_NAME, &key.name());
+            if let Some(parent) = parent {
+                desc.varint(TRACK_PARENT_UUID, parent);
+            }
+            if let TrackKey::Depth(_, _) = key {
+                desc.message(TRACK_COUNTER, |_| {});
+            }
+        });
+        self.write_packet(&packet)?;
+        Ok(uuid)
+    }
+
+    // 返回名字的iid，第一次出现时需要在packet中附带interned data
+    fn intern(&mut self, name: &str) -> (u64, bool) {
+        if let Some(iid) = self.names.get(name) {
+            return (*iid, false);
+        }
+        let iid = self.names.len() as u64 + 1;
+        self.names.insert(name.to_string(), iid);
+        (iid, true)
+    }
+
+    fn write_event(
+        &mut self,
+        time: u64,
+        track: u64,
+        event_type: u64,
+        name: Option<&str>,
+        annotations: &[(&str, &str)],
+    ) -> io::Result<()> {
+        let name = name.map(|x| (x, self.intern(x)));
+        let mut packet = Proto::default();
+        packet
+            .varint(PACKET_TIMESTAMP, self.clock_type.to_nanos(time))
+            .varint(PACKET_SEQUENCE_ID, SEQUENCE_ID)
+            .varint(PACKET_SEQUENCE_FLAGS, SEQ_NEEDS_INCREMENTAL_STATE);
+        if let Some((text, (iid, true))) = name {
+            packet.message(PACKET_INTERNED_DATA, |data| {
+                data.message(INTERNED_EVENT_NAMES, |event_name| {
+                    event_name
+                        .varint(EVENT_NAME_IID_FIELD, iid)
+                        .string(EVENT_NAME_NAME, text);
+                });
+            });
+        }
+        packet.message(PACKET_TRACK_EVENT, |event| {
+            event
+                .varint(EVENT_TYPE, event_type)
+                .varint(EVENT_TRACK_UUID, track);
+            if let Some((_, (iid, _))) = name {
+                event.varint(EVENT_NAME_IID, iid);
+            }
+            for (key, value) in annotations {
+                event.message(EVENT_DEBUG_ANNOTATIONS, |annotation| {
+                    annotation
+                        .string(ANNOTATION_NAME, key)
+                        .string(ANNOTATION_STRING_VALUE, value);
+                });
+            }
+        });
+        self.write_packet(&packet)
+    }
+
+    fn write_counter(&mut self, time: u64, track: u64, value: u64) -> io::Result<()> {
+        let mut packet = Proto::default();
+        packet
+            .varint(PACKET_TIMESTAMP, self.clock_type.to_nanos(time))
+            .varint(PACKET_SEQUENCE_ID, SEQUENCE_ID)
+            .message(PACKET_TRACK_EVENT, |event| {
+                event
+                    .varint(EVENT_TYPE, TYPE_COUNTER)
+                    .varint(EVENT_TRACK_UUID, track)
+                    .varint(EVENT_COUNTER_VALUE, value);
+            });
+        self.write_packet(&packet)
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> TraceSink for PerfettoSink<W> {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        self.write_record(event)?;
+        self.pending += 1;
+        if self.pending >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pending = 0;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> PerfettoSink<W> {
+    fn write_record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let (hart, ctx) = (event.hart, event.ctx);
+        let mut annotations = Vec::new();
+        if let Some(args) = event.args.as_deref() {
+            annotations.push(("args", args));
+        }
+        if let Some(ret) = event.ret.as_deref() {
+            annotations.push(("ret", ret));
+        }
+        match event.kind {
+            EventKind::Call | EventKind::Return | EventKind::Unwind => {
+                // 函数事件的名字是reader@name的形式
+                let (reader, name) = event
+                    .name
+                    .split_once('@')
+                    .unwrap_or(("unknown", &event.name));
+                let track = self.track(&TrackKey::Reader(hart, ctx, reader.to_string()))?;
+                if event.kind == EventKind::Call {
+                    self.write_event(
+                        event.time,
+                        track,
+                        TYPE_SLICE_BEGIN,
+                        Some(name),
+                        &annotations,
+                    )?;
+                } else {
+                    if event.kind == EventKind::Unwind {
+                        annotations.push(("unwound", "true"));
+                    }
+                    self.write_event(event.time, track, TYPE_SLICE_END, None, &annotations)?;
+                }
+                let depth = self.track(&TrackKey::Depth(hart, ctx))?;
+                self.write_counter(event.time, depth, event.depth as u64)
+            }
+            EventKind::Mark => {
+                let track = self.track(&TrackKey::Context(hart, ctx))?;
+                self.write_event(event.time, track, TYPE_INSTANT, Some(&event.name), &[])
+            }
+            EventKind::RegionBegin | EventKind::RegionEnd => {
+                let track = self.track(&TrackKey::Region(hart, event.name.clone()))?;
+                if event.kind == EventKind::RegionBegin {
+                    self.write_event(event.time, track, TYPE_SLICE_BEGIN, Some(&event.name), &[])
+                } else {
+                    self.write_event(event.time, track, TYPE_SLICE_END, None, &[])
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 解析出所有的TracePacket
+    fn packets(data: &[u8]) -> Vec<&[u8]> {
+        let mut res = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            assert!(data[pos] == 0x0a);
+            let (len, used) = varint(&data[pos + 1..]);
+            let start = pos + 1 + used;
+            res.push(&data[start..start + len as usize]);
+            pos = start + len as usize;
+        }
+        res
+    }
+
+    fn varint(data: &[u8]) -> (u64, usize) {
+        let mut value = 0;
+        for (idx, byte) in data.iter().enumerate() {
+            value |= ((byte & 0x7f) as u64) << (7 * idx);
+            if byte & 0x80 == 0 {
+                return (value, idx + 1);
+            }
+        }
+        panic!("truncated varint")
+    }
+
+    fn contains(packet: &[u8], text: &str) -> bool {
+        packet.windows(text.len()).any(|x| x == text.as_bytes())
+    }
+
+    fn event(kind: EventKind, time: u64, depth: usize, name: &str) -> TraceEvent {
+        TraceEvent {
+            kind,
+            hart: 0,
+            ctx: 0,
+            time,
+            depth,
+            name: name.to_string(),
+            args: None,
+            ret: None,
+        }
+    }
+
+    #[test]
+    fn test_proto() {
+        let mut proto = Proto::default();
+        proto.varint(1, 300).string(2, "hi");
+        assert!(proto.buf == [0x08, 0xac, 0x02, 0x12, 0x02, b'h', b'i']);
+    }
+
+    #[test]
+    fn test_perfetto_sink() {
+        let mut sink = PerfettoSink::new(Vec::new(), ClockType::WallMillis, 1).unwrap();
+        sink.record(&event(EventKind::Call, 1, 1, "dummy@main"))
+            .unwrap();
+        sink.record(&event(EventKind::Call, 2, 2, "pal@main"))
+            .unwrap();
+        sink.record(&event(EventKind::Return, 3, 1, "pal@main"))
+            .unwrap();
+        sink.record(&event(EventKind::Mark, 3, 1, "check")).unwrap();
+        let data = sink.into_inner();
+        let packets = packets(&data);
+        // 清空状态，hart，上下文，dummy，调用，深度轨道，计数，pal，调用，计数，返回，计数，标记
+        assert!(packets.len() == 13);
+        assert!(packets[0] == [0x50, 0x01, 0x68, 0x01]);
+        assert!(contains(packets[1], "hart 0"));
+        assert!(contains(packets[3], "dummy"));
+        // 第一次出现的名字带有interned data，之后只用iid
+        assert!(contains(packets[4], "main"));
+        assert!(!contains(packets[8], "main"));
+        // 时间转换为纳秒
+        assert!(packets[4].starts_with(&[0x40, 0xc0, 0x84, 0x3d]));
+        assert!(contains(packets[12], "check"));
+    }
+
+    #[test]
+    fn test_perfetto_flush() {
+        let writer = BufWriter::with_capacity(1 << 16, Vec::new());
+        let mut sink = PerfettoSink::new(writer, ClockType::WallMillis, 2).unwrap();
+        // 清空状态的packet在创建时就写入
+        let len = sink.writer.get_ref().len();
+        assert!(packets(sink.writer.get_ref()).len() == 1);
+        sink.record(&event(EventKind::Call, 1, 1, "dummy@main"))
+            .unwrap();
+        assert!(sink.writer.get_ref().len() == len);
+        sink.record(&event(EventKind::Return, 2, 0, "dummy@main"))
+            .unwrap();
+        assert!(sink.writer.get_ref().len() > len);
+        assert!(sink.pending == 0);
+    }
+}
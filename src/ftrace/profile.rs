@@ -0,0 +1,132 @@
+use super::manager::FuncKey;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Default)]
+pub struct ProfileEntry {
+    pub calls: u64,
+    // 包括所有子函数的时间
+    pub inclusive: u64,
+    // 去掉子函数之后函数自身的时间
+    pub exclusive: u64,
+    // min和max都是针对单次调用的inclusive时间
+    pub min: u64,
+    pub max: u64,
+}
+
+impl ProfileEntry {
+    pub fn mean(&self) -> f64 {
+        if self.calls == 0 {
+            0_f64
+        } else {
+            self.inclusive as f64 / self.calls as f64
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Profiler {
+    entries: HashMap<FuncKey, ProfileEntry>,
+}
+
+impl Profiler {
+    // 每一个函数实例结束时调用一次
+    pub fn record(&mut self, key: FuncKey, inclusive: u64, exclusive: u64) {
+        let entry = self.entries.entry(key).or_insert(ProfileEntry {
+            min: u64::MAX,
+            ..Default::default()
+        });
+        entry.calls += 1;
+        entry.inclusive += inclusive;
+        entry.exclusive += exclusive;
+        entry.min = entry.min.min(inclusive);
+        entry.max = entry.max.max(inclusive);
+    }
+
+    // 把其它hart的统计合并进来
+    pub fn merge(&mut self, other: &Profiler) {
+        for (key, other) in other.entries.iter() {
+            let entry = self.entries.entry(*key).or_insert(ProfileEntry {
+                min: u64::MAX,
+                ..Default::default()
+            });
+            entry.calls += other.calls;
+            entry.inclusive += other.inclusive;
+            entry.exclusive += other.exclusive;
+            entry.min = entry.min.min(other.min);
+            entry.max = entry.max.max(other.max);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, key: &FuncKey) -> Option<&ProfileEntry> {
+        self.entries.get(key)
+    }
+
+    pub fn total_exclusive(&self) -> u64 {
+        self.entries.values().map(|x| x.exclusive).sum()
+    }
+
+    // 按照self time从大到小排序，和gprof的flat profile一致
+    pub fn sorted(&self) -> Vec<(FuncKey, ProfileEntry)> {
+        let mut vec = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (*key, *entry))
+            .collect::<Vec<_>>();
+        vec.sort_by(|a, b| {
+            b.1.exclusive
+                .cmp(&a.1.exclusive)
+                .then(b.1.calls.cmp(&a.1.calls))
+        });
+        vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::elf_reader::FunType;
+    use super::super::manager::CurReader;
+    use super::*;
+
+    fn key(id: u32) -> FuncKey {
+        FuncKey {
+            reader: Some(CurReader::MainReader),
+            func_type: FunType::LocalFunc,
+            id,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut profiler = Profiler::default();
+        profiler.record(key(1), 10, 4);
+        profiler.record(key(1), 30, 10);
+        profiler.record(key(2), 20, 20);
+        let entry = profiler.get(&key(1)).unwrap();
+        assert!(entry.calls == 2);
+        assert!(entry.inclusive == 40);
+        assert!(entry.exclusive == 14);
+        assert!(entry.min == 10);
+        assert!(entry.max == 30);
+        assert!(entry.mean() == 20_f64);
+        assert!(profiler.total_exclusive() == 34);
+
+        let sorted = profiler.sorted();
+        assert!(sorted[0].0 == key(2));
+        assert!(sorted[1].0 == key(1));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut profiler = Profiler::default();
+        profiler.record(key(1), 10, 4);
+        let mut other = Profiler::default();
+        other.record(key(1), 30, 10);
+        other.record(key(2), 20, 20);
+        profiler.merge(&other);
+        let entry = profiler.get(&key(1)).unwrap();
+        assert!(entry.calls == 2 && entry.inclusive == 40 && entry.exclusive == 14);
+        assert!(entry.min == 10 && entry.max == 30);
+        assert!(profiler.get(&key(2)).unwrap().calls == 1);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum EventKind {
+    Call,
+    Return,
+    // 被sync_sp或者longjmp等跳过，没有经过ret就离开了栈
+    Unwind,
+    // 用户插入的标记和区间，name是标记的名字
+    Mark,
+    RegionBegin,
+    RegionEnd,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Call => "call",
+            EventKind::Return => "ret",
+            EventKind::Unwind => "unwind",
+            EventKind::Mark => "mark",
+            EventKind::RegionBegin => "begin",
+            EventKind::RegionEnd => "end",
+        }
+    }
+}
+
+// 由manager在压栈和弹栈的时候产生
+pub struct TraceEvent {
+    pub kind: EventKind,
+    pub hart: u32,
+    pub ctx: u64,
+    pub time: u64,
+    // 事件发生后的栈深度，call时包括自己，ret时不包括自己
+    pub depth: usize,
+    // reader@name的形式，标记事件是标记的名字
+    pub name: String,
+    // 开启show_context时call事件的参数列表
+    pub args: Option<String>,
+    // 开启show_context时ret事件的返回值，void函数没有
+    pub ret: Option<String>,
+}
+
+pub trait TraceSink {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+// 多个hart的manager共享同一个sink
+pub type SharedSink = Arc<Mutex<dyn TraceSink + Send>>;
+
+// 把事件按行追加写入文件，每flush_interval个事件刷新一次，
+// 这样即使模拟器中途abort，文件里也保留了大部分的trace
+pub struct StreamSink {
+    writer: BufWriter<File>,
+    flush_interval: usize,
+    pending: usize,
+}
+
+impl StreamSink {
+    pub fn new(path: &str, flush_interval: usize, unit: &str) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "# time unit: {}", unit)?;
+        writeln!(writer, "# time hart ctx depth event function")?;
+        writer.flush()?;
+        Ok(StreamSink {
+            writer,
+            flush_interval: flush_interval.max(1),
+            pending: 0,
+        })
+    }
+}
+
+impl TraceSink for StreamSink {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        write!(
+            self.writer,
+            "{} {} {} {} {} {}{}",
+            event.time,
+            event.hart,
+            event.ctx,
+            event.depth,
+            event.kind.as_str(),
+            event.name,
+            event.args.as_deref().unwrap_or("")
+        )?;
+        if let Some(ret) = event.ret.as_ref() {
+            write!(self.writer, " = {}", ret)?;
+        }
+        writeln!(self.writer)?;
+        self.pending += 1;
+        if self.pending >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pending = 0;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn event(kind: EventKind, time: u64) -> TraceEvent {
+        TraceEvent {
+            kind,
+            hart: 0,
+            ctx: 0,
+            time,
+            depth: 1,
+            name: "dummy@main".to_string(),
+            args: None,
+            ret: None,
+        }
+    }
+
+    #[test]
+    fn test_stream_flush() {
+        let path = "./target/stream_flush.txt";
+        let mut sink = StreamSink::new(path, 2, "inst").unwrap();
+        sink.record(&event(EventKind::Call, 1)).unwrap();
+        // 还没有到刷新的间隔
+        assert!(fs::read_to_string(path).unwrap().lines().count() == 2);
+        sink.record(&event(EventKind::Return, 5)).unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines.len() == 4);
+        assert!(lines[2] == "1 0 0 1 call dummy@main");
+        assert!(lines[3] == "5 0 0 1 ret dummy@main");
+
+        let mut call = event(EventKind::Call, 7);
+        call.args = Some("(a0=0x1)".to_string());
+        sink.record(&call).unwrap();
+        sink.flush().unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        assert!(text.lines().last() == Some("7 0 0 1 call dummy@main(a0=0x1)"));
+        let mut ret = event(EventKind::Return, 9);
+        ret.ret = Some("-1".to_string());
+        sink.record(&ret).unwrap();
+        sink.flush().unwrap();
+        let text = fs::read_to_string(path).unwrap();
+        assert!(text.lines().last() == Some("9 0 0 1 ret dummy@main = -1"));
+    }
+}
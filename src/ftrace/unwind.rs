@@ -0,0 +1,306 @@
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, Register, RegisterRule, RunTimeEndian,
+    UnwindContext, UnwindSection,
+};
+
+use super::guest_mem::GuestMem;
+
+// riscv的DWARF寄存器编号和x0到x31相同
+pub const REG_RA: usize = 1;
+pub const REG_SP: usize = 2;
+pub const REG_FP: usize = 8;
+const REG_NUM: usize = 32;
+// 回溯的最大深度，防止栈被破坏时死循环
+pub const MAX_UNWIND_DEPTH: usize = 256;
+
+// 寄存器在调用者栈帧中的值如何恢复
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegRule {
+    // 无法恢复，ra被标记为未定义说明到达了最外层
+    Undefined,
+    SameValue,
+    // 保存在CFA+offset的内存中
+    Offset(i64),
+    // 值就是CFA+offset
+    ValOffset(i64),
+    // 保存在另一个寄存器中
+    Register(u16),
+}
+
+// 某个pc处的栈帧布局，CFA是调用这个函数之前的sp
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameRule {
+    pub cfa_reg: u16,
+    pub cfa_offset: i64,
+    pub regs: Vec<(u16, RegRule)>,
+}
+
+// 一个ELF中的CFI数据，地址都是链接时的地址
+#[derive(Clone, Default)]
+pub struct CfiTable {
+    // .eh_frame的地址和内容
+    eh_frame: Option<(u64, Vec<u8>)>,
+    debug_frame: Option<Vec<u8>>,
+    text_addr: u64,
+    little_endian: bool,
+    // 运行时地址减去链接时地址
+    bias: u64,
+}
+
+impl CfiTable {
+    // load返回段的地址和内容
+    pub fn load(mut load: impl FnMut(&str) -> Option<(u64, Vec<u8>)>, little_endian: bool) -> Self {
+        CfiTable {
+            eh_frame: load(".eh_frame").filter(|(_, data)| !data.is_empty()),
+            debug_frame: load(".debug_frame")
+                .map(|(_, data)| data)
+                .filter(|data| !data.is_empty()),
+            text_addr: load(".text").map_or(0, |(addr, _)| addr),
+            little_endian,
+            bias: 0,
+        }
+    }
+
+    pub fn with_bias(mut self, bias: u64) -> Self {
+        self.bias = self.bias.wrapping_add(bias);
+        self
+    }
+
+    fn endian(&self) -> RunTimeEndian {
+        if self.little_endian {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        }
+    }
+
+    // pc处的栈帧规则，没有CFI或者规则无法表示时返回None
+    pub fn rule(&self, pc: u64) -> Option<FrameRule> {
+        let addr = pc.wrapping_sub(self.bias);
+        let endian = self.endian();
+        let mut ctx = UnwindContext::new();
+        if let Some((eh_addr, data)) = self.eh_frame.as_ref() {
+            let mut section = EhFrame::new(data, endian);
+            section.set_address_size(8);
+            let bases = BaseAddresses::default()
+                .set_eh_frame(*eh_addr)
+                .set_text(self.text_addr);
+            if let Ok(row) =
+                section.unwind_info_for_address(&bases, &mut ctx, addr, EhFrame::cie_from_offset)
+            {
+                return Self::convert(row);
+            }
+        }
+        if let Some(data) = self.debug_frame.as_ref() {
+            let mut section = DebugFrame::new(data, endian);
+            section.set_address_size(8);
+            let bases = BaseAddresses::default().set_text(self.text_addr);
+            if let Ok(row) =
+                section.unwind_info_for_address(&bases, &mut ctx, addr, DebugFrame::cie_from_offset)
+            {
+                return Self::convert(row);
+            }
+        }
+        None
+    }
+
+    fn convert(row: &gimli::UnwindTableRow<usize>) -> Option<FrameRule> {
+        let CfaRule::RegisterAndOffset { register, offset } = row.cfa() else {
+            // 不支持DWARF表达式
+            return None;
+        };
+        let regs = row
+            .registers()
+            .map(|(reg, rule)| {
+                let rule = match rule {
+                    RegisterRule::SameValue => RegRule::SameValue,
+                    RegisterRule::Offset(x) => RegRule::Offset(*x),
+                    RegisterRule::ValOffset(x) => RegRule::ValOffset(*x),
+                    RegisterRule::Register(Register(x)) => RegRule::Register(*x),
+                    _ => RegRule::Undefined,
+                };
+                (reg.0, rule)
+            })
+            .collect();
+        Some(FrameRule {
+            cfa_reg: register.0,
+            cfa_offset: *offset,
+            regs,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnwindMethod {
+    // 当前的寄存器
+    Registers,
+    Cfi,
+    FramePointer,
+}
+
+impl UnwindMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnwindMethod::Registers => "regs",
+            UnwindMethod::Cfi => "cfi",
+            UnwindMethod::FramePointer => "fp",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BtFrame {
+    pub pc: u64,
+    pub sp: u64,
+    // 这一帧是怎么得到的
+    pub method: UnwindMethod,
+}
+
+type Regs = [Option<u64>; REG_NUM];
+
+fn offset(base: u64, offset: i64) -> u64 {
+    base.wrapping_add(offset as u64)
+}
+
+// 按照CFI规则恢复调用者的寄存器
+fn step_cfi(regs: &Regs, rule: &FrameRule, mem: Option<&GuestMem>) -> Option<Regs> {
+    let cfa = offset((*regs.get(rule.cfa_reg as usize)?)?, rule.cfa_offset);
+    // 没有规则的寄存器保持不变，比如叶子函数中的ra
+    let mut caller = *regs;
+    for (reg, reg_rule) in rule.regs.iter() {
+        let Some(slot) = caller.get_mut(*reg as usize) else {
+            continue;
+        };
+        *slot = match reg_rule {
+            RegRule::Undefined => None,
+            RegRule::SameValue => regs[*reg as usize],
+            RegRule::Offset(x) => mem.and_then(|mem| mem.read_u64(offset(cfa, *x))),
+            RegRule::ValOffset(x) => Some(offset(cfa, *x)),
+            RegRule::Register(x) => regs.get(*x as usize).copied().flatten(),
+        };
+    }
+    caller[REG_SP] = Some(cfa);
+    Some(caller)
+}
+
+// riscv的帧指针约定：s0指向CFA，ra保存在s0-8，调用者的s0保存在s0-16
+fn step_fp(regs: &Regs, mem: Option<&GuestMem>) -> Option<Regs> {
+    let mem = mem?;
+    let fp = regs[REG_FP].filter(|x| *x != 0)?;
+    let mut caller = *regs;
+    caller[REG_RA] = Some(mem.read_u64(fp.wrapping_sub(8))?);
+    caller[REG_FP] = mem.read_u64(fp.wrapping_sub(16));
+    caller[REG_SP] = Some(fp);
+    Some(caller)
+}
+
+// 从当前的寄存器开始回溯，rule_for给出pc处的CFI规则，is_code判断pc是否在已知的程序中
+// 返回的第一帧是当前的pc
+pub fn unwind(
+    pc: u64,
+    regs: &[u64],
+    mem: Option<&GuestMem>,
+    rule_for: impl Fn(u64) -> Option<FrameRule>,
+    is_code: impl Fn(u64) -> bool,
+) -> Vec<BtFrame> {
+    let mut cur: Regs = [None; REG_NUM];
+    for (slot, value) in cur.iter_mut().zip(regs.iter()) {
+        *slot = Some(*value);
+    }
+    cur[0] = Some(0);
+    let mut frames = vec![BtFrame {
+        pc,
+        sp: cur[REG_SP].unwrap_or(0),
+        method: UnwindMethod::Registers,
+    }];
+    let mut pc = pc;
+    while frames.len() < MAX_UNWIND_DEPTH {
+        // 返回地址是调用指令的下一条，用它的前一个字节查找，避免落到下一个函数里
+        let lookup = if frames.len() == 1 { pc } else { pc - 1 };
+        let (caller, method) = match rule_for(lookup) {
+            Some(rule) => (step_cfi(&cur, &rule, mem), UnwindMethod::Cfi),
+            None => (step_fp(&cur, mem), UnwindMethod::FramePointer),
+        };
+        let Some(caller) = caller else {
+            break;
+        };
+        let (Some(ret), Some(sp)) = (caller[REG_RA], caller[REG_SP]) else {
+            break;
+        };
+        let last_sp = frames.last().map_or(0, |x| x.sp);
+        // 栈向下增长，调用者的sp不可能更小，sp和pc都不变说明没有进展
+        if ret == 0 || !is_code(ret) || sp < last_sp || (sp == last_sp && ret == pc) {
+            break;
+        }
+        frames.push(BtFrame {
+            pc: ret,
+            sp,
+            method,
+        });
+        pc = ret;
+        cur = caller;
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unwind_fp() {
+        // main(fp=0x7f00) -> foo(fp=0x7e00) -> bar(fp=0x7d00)
+        let mem = GuestMem::dummy_stack(&[
+            (0x7cf8, 0x1208),
+            (0x7cf0, 0x7e00),
+            (0x7df8, 0x1108),
+            (0x7df0, 0x7f00),
+            (0x7ef8, 0),
+            (0x7ef0, 0),
+        ]);
+        let mut regs = [0; 32];
+        regs[REG_SP] = 0x7cc0;
+        regs[REG_FP] = 0x7d00;
+        let frames = unwind(0x1310, &regs, Some(&mem), |_| None, |pc| pc >= 0x1000);
+        let pcs = frames.iter().map(|x| x.pc).collect::<Vec<_>>();
+        assert!(pcs == [0x1310, 0x1208, 0x1108]);
+        assert!(frames[1].method == UnwindMethod::FramePointer);
+        assert!(frames[2].sp == 0x7e00);
+    }
+
+    #[test]
+    fn test_unwind_cfi() {
+        // 叶子函数没有保存ra，调用者把ra保存在CFA-8
+        let leaf = FrameRule {
+            cfa_reg: REG_SP as u16,
+            cfa_offset: 0,
+            regs: Vec::new(),
+        };
+        let caller = FrameRule {
+            cfa_reg: REG_SP as u16,
+            cfa_offset: 16,
+            regs: vec![(REG_RA as u16, RegRule::Offset(-8))],
+        };
+        // 最外层的函数把ra标记为未定义
+        let outer = FrameRule {
+            cfa_reg: REG_SP as u16,
+            cfa_offset: 16,
+            regs: vec![(REG_RA as u16, RegRule::Undefined)],
+        };
+        let mem = GuestMem::dummy_stack(&[(0x7e08, 0x2010)]);
+        let mut regs = [0; 32];
+        regs[REG_SP] = 0x7e00;
+        regs[REG_RA] = 0x1104;
+        let rule_for = |pc: u64| match pc {
+            0x1200..=0x12ff => Some(leaf.clone()),
+            0x1100..=0x11ff => Some(caller.clone()),
+            0x2000..=0x20ff => Some(outer.clone()),
+            _ => None,
+        };
+        let frames = unwind(0x1210, &regs, Some(&mem), rule_for, |pc| pc >= 0x1000);
+        let pcs = frames.iter().map(|x| x.pc).collect::<Vec<_>>();
+        assert!(pcs == [0x1210, 0x1104, 0x2010]);
+        assert!(frames[2].sp == 0x7e10);
+        assert!(frames.iter().skip(1).all(|x| x.method == UnwindMethod::Cfi));
+    }
+}
@@ -1,14 +1,33 @@
 mod ftrace;
 mod utils;
 
+// 导出给C的接口的命名：build_builder之前的配置接口（set_*、add_*）、check_instruction和print_*
+// 沿用原来不带前缀的名字，build_builder之后在运行过程中调用的接口都带ftrace_前缀
+
 // 由于libc的绑定比std的ffi更全，所以不使用ffi的c_char等类型
-use libc::{c_char, c_uchar};
-use std::ffi::CStr;
+use libc::{c_char, c_int, c_uchar, c_void};
+use std::ffi::{CStr, CString};
+use std::sync::Arc;
 
 pub const RC_ERROR_CODE: isize = -1;
 pub const RC_SUCCESS_CODE: isize = 0;
+// check_instruction返回它表示有回调请求模拟器停下来
+pub const RC_STOP_CODE: isize = 1;
+// check_instruction返回它表示这条指令是ftrace的魔数指令，模拟器需要跳过它，
+// 否则魔数ecall会被当作真正的系统调用交给客户机内核
+pub const RC_MAGIC_CODE: isize = 2;
 pub const MAX_PATH_LEN: usize = 300;
 
+// set_clock可以选择的时钟
+pub const CLOCK_WALL_MILLIS: u32 = 0;
+pub const CLOCK_INSTRET: u32 = 1;
+pub const CLOCK_CYCLE: u32 = 2;
+pub const CLOCK_MTIME: u32 = 3;
+pub const CLOCK_MONOTONIC_NANOS: u32 = 4;
+
+// ftrace_load_elf的asid传入它表示程序在所有地址空间都可见，asid最多只有16位，不会冲突
+pub const ASID_GLOBAL: u64 = u64::MAX;
+
 #[no_mangle]
 pub extern "C" fn add_rust(left: usize, right: usize) -> usize {
     left + right
@@ -87,6 +106,127 @@ pub extern "C" fn add_prog_path(path: *const c_char) -> isize {
     }
 }
 
+#[no_mangle]
+// asid是satp中的ASID字段，ftrace_set_satp按照它切换上下文；直接调用ftrace_switch_context时使用同样的编号
+pub extern "C" fn add_prog_path_asid(path: *const c_char, asid: u64) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::add_prog_path_asid(path, asid).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_clock(clock_type: u32) -> isize {
+    if ftrace::set_clock(clock_type).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn add_nonlocal_exit(name: *const c_char) -> isize {
+    if let Ok(name) = get_string(name, MAX_PATH_LEN) {
+        if ftrace::add_nonlocal_exit(name).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 没有调试信息时，按照这里给出的参数个数记录a0到a7
+pub extern "C" fn add_signature(name: *const c_char, arity: usize, variadic: bool) -> isize {
+    if let Ok(name) = get_string(name, MAX_PATH_LEN) {
+        if ftrace::add_signature(name, arity, variadic).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 把第index个参数（从0开始）当作char*，需要先设置读取内存的回调
+// 可以和add_signature以任意顺序调用，也会覆盖DWARF中这个参数的类型
+pub extern "C" fn add_string_arg(name: *const c_char, index: usize) -> isize {
+    if let Ok(name) = get_string(name, MAX_PATH_LEN) {
+        if ftrace::add_string_arg(name, index).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 字符串参数最多读取的字节数，超过的部分用...表示
+pub extern "C" fn set_string_cap(string_cap: usize) -> isize {
+    if ftrace::set_string_cap(string_cap).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 客户机执行slli x0, x0, base+N时执行操作N：0打开，1暂停，2标记，3区间开始，4区间结束，5输出栈
+// 标记的名字是a0指向的字符串，需要设置ftrace_set_mem_reader
+pub extern "C" fn set_magic_hint(base: u32) -> isize {
+    if ftrace::set_magic_hint(base).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 客户机执行a7等于num的ecall时执行a1给出的操作，编号和set_magic_hint相同
+// check_instruction对这条ecall返回RC_MAGIC_CODE，模拟器需要跳过它而不是交给客户机内核
+pub extern "C" fn set_magic_ecall(num: u64) -> isize {
+    if ftrace::set_magic_ecall(num).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 客户机请求输出栈时写入的文件
+pub extern "C" fn set_magic_dump_path(path: *const c_char) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::set_magic_dump_path(path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 为true时build之后先不记录trace，等待客户机打开
+pub extern "C" fn set_start_paused(paused: bool) -> isize {
+    if ftrace::set_start_paused(paused).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn build_builder() -> isize {
     if ftrace::build_builder().is_ok() {
@@ -96,18 +236,446 @@ pub extern "C" fn build_builder() -> isize {
     }
 }
 
+#[no_mangle]
+// 清空追踪的状态，保留builder的配置，之后可以再次调用build_builder
+pub extern "C" fn ftrace_reset() -> isize {
+    if ftrace::reset().is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 清空追踪的状态并释放builder，之后需要重新start_builder
+pub extern "C" fn ftrace_destroy() -> isize {
+    if ftrace::destroy().is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 环形缓冲区模式，只保留最近的capacity条记录，0表示不限制
+pub extern "C" fn set_log_capacity(capacity: usize) -> isize {
+    if ftrace::set_log_capacity(capacity).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 环形缓冲区模式，只保留最近window个时间单位内的记录，0表示不限制
+pub extern "C" fn set_log_window(window: u64) -> isize {
+    if ftrace::set_log_window(window).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 为false时不在内存中保留调用记录，配合set_stream_path或set_perfetto_path长时间运行，
+// print_stack仍然可以输出当前的栈
+pub extern "C" fn set_keep_log(keep_log: bool) -> isize {
+    if ftrace::set_keep_log(keep_log).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 运行过程中把call/ret事件追加写入这个文件，即使模拟器中途退出也能保留trace
+pub extern "C" fn set_stream_path(path: *const c_char) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::set_stream_path(path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 运行过程中把事件写成Perfetto的二进制trace，比Chrome JSON小得多
+pub extern "C" fn set_perfetto_path(path: *const c_char) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::set_perfetto_path(path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// stream和perfetto的文件每写入interval个事件刷新一次
+pub extern "C" fn set_stream_flush_interval(interval: usize) -> isize {
+    if ftrace::set_stream_flush_interval(interval).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ftrace_flush_trace() -> isize {
+    if ftrace::flush_trace().is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 按函数名过滤，pattern默认是glob，以"re:"开头时是正则表达式
+// include为true时只保留匹配的函数，为false时去掉匹配的函数
+pub extern "C" fn add_filter_func(pattern: *const c_char, include: bool) -> isize {
+    if let Ok(pattern) = get_string(pattern, MAX_PATH_LEN) {
+        if ftrace::add_filter_func(pattern, include).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 按reader（ELF文件名，不带扩展名）过滤
+pub extern "C" fn add_filter_reader(reader: *const c_char, include: bool) -> isize {
+    if let Ok(reader) = get_string(reader, MAX_PATH_LEN) {
+        if ftrace::add_filter_reader(reader, include).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 栈深度超过max_depth的函数不输出，0表示不限制
+pub extern "C" fn set_filter_depth(max_depth: usize) -> isize {
+    if ftrace::set_filter_depth(max_depth).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 在build_builder之前调用，默认只有一个hart
+pub extern "C" fn set_hart_num(hart_num: usize) -> isize {
+    if ftrace::set_hart_num(hart_num).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
 #[no_mangle]
 // 这里有一个假设，就是只传入32个寄存器，不能多不能少
 pub extern "C" fn check_instruction(pc: u64, inst: u32, regs: *const u64) -> isize {
+    ftrace_check_instruction_hart(0, pc, inst, regs)
+}
+
+#[no_mangle]
+// regs由C侧保证指向32个寄存器
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn ftrace_check_instruction_hart(
+    hart: usize,
+    pc: u64,
+    inst: u32,
+    regs: *const u64,
+) -> isize {
     if !regs.is_null() {
         let slice: &[u64] = unsafe { std::slice::from_raw_parts(regs, 32) };
-        ftrace::check_instruction(pc, inst, slice);
+        match ftrace::check_instruction_hart(hart, pc, inst, slice) {
+            Ok(ftrace::CheckResult::Stop) => RC_STOP_CODE,
+            Ok(ftrace::CheckResult::Magic) => RC_MAGIC_CODE,
+            Ok(ftrace::CheckResult::Continue) => RC_SUCCESS_CODE,
+            Err(_) => RC_ERROR_CODE,
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 在运行中途开始追踪，比如在sdb中打开ftrace，需要先用ftrace_set_mem_reader设置读取内存的回调
+// 成功时返回回溯得到的栈帧数，失败时返回RC_ERROR_CODE
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn ftrace_attach(hart: usize, pc: u64, regs: *const u64) -> isize {
+    if regs.is_null() {
+        return RC_ERROR_CODE;
+    }
+    let regs: &[u64] = unsafe { std::slice::from_raw_parts(regs, 32) };
+    match ftrace::attach(hart, pc, regs) {
+        Ok(depth) => depth as isize,
+        Err(_) => RC_ERROR_CODE,
+    }
+}
+
+#[no_mangle]
+// 不依赖影子栈，用CFI或者帧指针回溯，需要先用ftrace_set_mem_reader设置读取内存的回调
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn print_backtrace(
+    hart: usize,
+    pc: u64,
+    regs: *const u64,
+    path: *const c_char,
+) -> isize {
+    if regs.is_null() {
+        return RC_ERROR_CODE;
+    }
+    let regs: &[u64] = unsafe { std::slice::from_raw_parts(regs, 32) };
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::print_backtrace(hart, pc, regs, path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ftrace_set_time(value: u64) -> isize {
+    ftrace_set_time_hart(0, value)
+}
+
+#[no_mangle]
+pub extern "C" fn ftrace_set_time_hart(hart: usize, value: u64) -> isize {
+    if ftrace::set_time(hart, value).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ftrace_switch_context(ctx: u64) -> isize {
+    ftrace_switch_context_hart(0, ctx)
+}
+
+#[no_mangle]
+pub extern "C" fn ftrace_switch_context_hart(hart: usize, ctx: u64) -> isize {
+    if ftrace::switch_context(hart, ctx).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 在satp被写入之后调用，按照其中的ASID切换上下文
+pub extern "C" fn ftrace_set_satp(satp: u64) -> isize {
+    ftrace_set_satp_hart(0, satp)
+}
+
+#[no_mangle]
+pub extern "C" fn ftrace_set_satp_hart(hart: usize, satp: u64) -> isize {
+    if ftrace::set_satp(hart, satp).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+// 传给C回调的函数信息，指针只在回调期间有效
+#[repr(C)]
+pub struct FtraceHookInfo {
+    pub hart: u32,
+    pub ctx: u64,
+    pub name: *const c_char,
+    pub reader: *const c_char,
+    pub addr: u64,
+    // a0到a7，没有记录寄存器的时候args为NULL，args_len为0
+    pub args: *const u64,
+    pub args_len: usize,
+    // 只有正常返回的时候has_ret为true
+    pub has_ret: bool,
+    pub ret_val: u64,
+    pub ret_val2: u64,
+}
+
+// 返回非0表示请求模拟器停下来
+pub type FtraceHookFn =
+    Option<extern "C" fn(info: *const FtraceHookInfo, user_data: *mut c_void) -> c_int>;
+
+// user_data由C侧管理，ftrace只把它原样传回回调。
+// 多个hart在不同线程上运行时，C侧需要保证回调和user_data可以被并发使用
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    // 通过方法取出指针，闭包才会捕获整个UserData而不是其中的裸指针
+    fn ptr(self) -> *mut c_void {
+        self.0
+    }
+}
+
+fn wrap_hook(hook: FtraceHookFn, user_data: *mut c_void) -> Option<ftrace::HookFn> {
+    let hook = hook?;
+    let user_data = UserData(user_data);
+    Some(Arc::new(move |info: &ftrace::HookInfo| {
+        let name = CString::new(info.name).unwrap_or_default();
+        let reader = CString::new(info.reader).unwrap_or_default();
+        let (args, args_len) = match info.args {
+            Some(args) => (args.as_ptr(), args.len()),
+            None => (std::ptr::null(), 0),
+        };
+        let (ret_val, ret_val2) = info
+            .ret_val
+            .map(|(a0, a1)| (a0, a1.unwrap_or(0)))
+            .unwrap_or((0, 0));
+        let c_info = FtraceHookInfo {
+            hart: info.hart,
+            ctx: info.ctx,
+            name: name.as_ptr(),
+            reader: reader.as_ptr(),
+            addr: info.addr,
+            args,
+            args_len,
+            has_ret: info.ret_val.is_some(),
+            ret_val,
+            ret_val2,
+        };
+        hook(&c_info, user_data.ptr()) != 0
+    }))
+}
+
+#[no_mangle]
+// 在build_builder之后调用，name_or_addr是函数名或者0x开头的函数起始地址
+// on_enter和on_exit可以有一个为NULL
+// 回调在check_instruction释放锁之后执行，回调中可以调用ftrace_mark、print_stack等接口
+pub extern "C" fn ftrace_register_hook(
+    name_or_addr: *const c_char,
+    on_enter: FtraceHookFn,
+    on_exit: FtraceHookFn,
+    user_data: *mut c_void,
+) -> isize {
+    if let Ok(target) = get_string(name_or_addr, MAX_PATH_LEN) {
+        let on_enter = wrap_hook(on_enter, user_data);
+        let on_exit = wrap_hook(on_exit, user_data);
+        if ftrace::register_hook(&target, on_enter, on_exit).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+// 读取客户机内存，返回实际读取的字节数，失败时返回负数
+pub type FtraceMemReadFn = Option<extern "C" fn(addr: u64, buf: *mut c_uchar, len: usize) -> c_int>;
+
+#[no_mangle]
+// 在build_builder之后调用，传入NULL表示取消
+// 回调在ftrace持有锁的时候执行，回调中调用ftrace的接口会直接返回错误
+pub extern "C" fn ftrace_set_mem_reader(read_guest_mem: FtraceMemReadFn) -> isize {
+    let read = read_guest_mem.map(|read| -> ftrace::MemReadFn {
+        Arc::new(move |addr, buf: &mut [u8]| {
+            let n = read(addr, buf.as_mut_ptr(), buf.len());
+            usize::try_from(n).ok()
+        })
+    });
+    if ftrace::set_mem_reader(read).is_ok() {
         RC_SUCCESS_CODE
     } else {
         RC_ERROR_CODE
     }
 }
 
+#[no_mangle]
+// 运行时加载新的程序，比如nanos-lite的loader，符号整体平移base
+// asid和add_prog_path_asid一样是satp中的ASID字段，ASID_GLOBAL表示在所有地址空间可见
+// 成功时返回reader的id（大于0），失败时返回RC_ERROR_CODE
+pub extern "C" fn ftrace_load_elf(path: *const c_char, base: u64, asid: u64) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        let asid = (asid != ASID_GLOBAL).then_some(asid);
+        match ftrace::load_elf(path, base, asid) {
+            Ok(id) => id as isize,
+            Err(_) => RC_ERROR_CODE,
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 卸载之后新的调用不会再匹配到这个程序，栈上已有的函数依然可以正常显示
+pub extern "C" fn ftrace_unload_elf(id: u32) -> isize {
+    if ftrace::unload_elf(id).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 为true时ret找不到合理的目标就panic，默认记录下来并从返回的目标重建栈
+pub extern "C" fn set_strict_mode(strict: bool) -> isize {
+    if ftrace::set_strict_mode(strict).is_ok() {
+        RC_SUCCESS_CODE
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 返回所有hart从失去同步中恢复的总次数，manager不存在时返回RC_ERROR_CODE
+pub extern "C" fn ftrace_desync_count() -> isize {
+    match ftrace::desync_count() {
+        Ok(count) => count as isize,
+        Err(_) => RC_ERROR_CODE,
+    }
+}
+
+fn mark(kind: ftrace::MarkerKind, label: *const c_char) -> isize {
+    if let Ok(label) = get_string(label, MAX_PATH_LEN) {
+        if ftrace::mark(kind, &label).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 在所有hart的trace中插入一个带时间戳的标记，比如区分不同的测试用例
+pub extern "C" fn ftrace_mark(label: *const c_char) -> isize {
+    mark(ftrace::MarkerKind::Instant, label)
+}
+
+#[no_mangle]
+// 区间按名字配对，可以嵌套
+pub extern "C" fn ftrace_region_begin(label: *const c_char) -> isize {
+    mark(ftrace::MarkerKind::Begin, label)
+}
+
+#[no_mangle]
+// 没有对应的ftrace_region_begin时返回RC_ERROR_CODE
+pub extern "C" fn ftrace_region_end(label: *const c_char) -> isize {
+    mark(ftrace::MarkerKind::End, label)
+}
+
 #[no_mangle]
 pub extern "C" fn print_stack(path: *const c_char) -> isize {
     if let Ok(path) = get_string(path, MAX_PATH_LEN) {
@@ -121,6 +689,60 @@ pub extern "C" fn print_stack(path: *const c_char) -> isize {
     }
 }
 
+#[no_mangle]
+// 导出Chrome Trace Event Format的JSON，每个hart是一个进程，每个上下文是一个线程
+pub extern "C" fn print_chrome_trace(path: *const c_char) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::print_chrome_trace(path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+// 导出折叠栈格式，用flamegraph.pl或者inferno画火焰图
+pub extern "C" fn print_folded(path: *const c_char) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::print_folded(path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn print_profile(path: *const c_char) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::print_profile(path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn print_call_graph(path: *const c_char) -> isize {
+    if let Ok(path) = get_string(path, MAX_PATH_LEN) {
+        if ftrace::print_call_graph(path).is_ok() {
+            RC_SUCCESS_CODE
+        } else {
+            RC_ERROR_CODE
+        }
+    } else {
+        RC_ERROR_CODE
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;